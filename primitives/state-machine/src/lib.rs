@@ -124,6 +124,10 @@ pub use crate::overlayed_changes::{
 	OffchainChangesCollection,
 	OffchainOverlayedChanges,
 	IndexOperation,
+	ChangesTrieInput, ExtrinsicsCollection, BackendMut,
+	ChildWriteError, UnknownChild, validate_child_keyspace,
+	BackendIter, MergedStorageIter, InvalidChildKeyspace, OverlayState,
+	TransactionOutcome, ExtrinsicIndex, OverlayOp, ChangesSummary, TxToken, TxScopeError,
 };
 pub use crate::backend::Backend;
 pub use crate::trie_backend_essence::{TrieBackendStorage, Storage};
@@ -17,15 +17,15 @@
 
 //! Houses the code that implements the transactional overlay storage.
 
-use super::{StorageKey, StorageValue, Extrinsics};
+use super::{StorageKey, StorageValue, Extrinsics, ExtrinsicIndex};
 
 #[cfg(feature = "std")]
 use std::collections::HashSet as Set;
 #[cfg(not(feature = "std"))]
 use sp_std::collections::btree_set::BTreeSet as Set;
 
-use sp_std::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
-use sp_std::hash::Hash;
+use sp_std::collections::{btree_map::{BTreeMap, Entry}, btree_set::BTreeSet};
+use sp_std::{hash::Hash, sync::Arc};
 use smallvec::SmallVec;
 use crate::warn;
 
@@ -34,7 +34,7 @@ const PROOF_OVERLAY_NON_EMPTY: &str = "\
 	as the last transaction is removed; qed";
 
 type DirtyKeysSets<K> = SmallVec<[Set<K>; 5]>;
-type Transactions<V> = SmallVec<[InnerValue<V>; 5]>;
+type Transactions<V, I> = SmallVec<[InnerValue<V, I>; 5]>;
 
 /// Error returned when trying to commit or rollback while no transaction is open or
 /// when the runtime is trying to close a transaction started by the client.
@@ -63,24 +63,24 @@ pub enum ExecutionMode {
 
 #[derive(Debug, Default, Clone)]
 #[cfg_attr(test, derive(PartialEq))]
-struct InnerValue<V> {
+struct InnerValue<V, I = ExtrinsicIndex> {
 	/// Current value. None if value has been deleted.
 	value: V,
 	/// The set of extrinsic indices where the values has been changed.
 	/// Is filled only if runtime has announced changes trie support.
-	extrinsics: Extrinsics,
+	extrinsics: Extrinsics<I>,
 }
 
 /// An overlay that contains all versions of a value for a specific key.
 #[derive(Debug, Clone)]
 #[cfg_attr(test, derive(PartialEq))]
-pub struct OverlayedEntry<V> {
+pub struct OverlayedEntry<V, I = ExtrinsicIndex> {
 	/// The individual versions of that value.
 	/// One entry per transactions during that the value was actually written.
-	transactions: Transactions<V>,
+	transactions: Transactions<V, I>,
 }
 
-impl<V> Default for OverlayedEntry<V> {
+impl<V, I> Default for OverlayedEntry<V, I> {
 	fn default() -> Self {
 		Self {
 			transactions: SmallVec::new(),
@@ -95,10 +95,12 @@ pub type OverlayedValue = OverlayedEntry<Option<StorageValue>>;
 pub type OverlayedChangeSet = OverlayedMap<StorageKey, Option<StorageValue>>;
 
 /// Holds a set of changes with the ability modify them using nested transactions.
+///
+/// Changes are attributed to extrinsics using indices of type `I`.
 #[derive(Debug, Clone)]
-pub struct OverlayedMap<K: Ord + Hash, V> {
+pub struct OverlayedMap<K: Ord + Hash, V, I = ExtrinsicIndex> {
 	/// Stores the changes that this overlay constitutes.
-	changes: BTreeMap<K, OverlayedEntry<V>>,
+	changes: BTreeMap<K, OverlayedEntry<V, I>>,
 	/// Stores which keys are dirty per transaction. Needed in order to determine which
 	/// values to merge into the parent transaction on commit. The length of this vector
 	/// therefore determines how many nested transactions are currently open (depth).
@@ -109,15 +111,19 @@ pub struct OverlayedMap<K: Ord + Hash, V> {
 	num_client_transactions: usize,
 	/// Determines whether the node is using the overlay from the client or the runtime.
 	execution_mode: ExecutionMode,
+	/// Committed entries of a base map. They are visible for every key that `changes` holds
+	/// no entry for and are copied into `changes` on the first write to their key.
+	base: Option<Arc<BTreeMap<K, OverlayedEntry<V, I>>>>,
 }
 
-impl<K: Ord + Hash, V> Default for OverlayedMap<K, V> {
+impl<K: Ord + Hash, V, I> Default for OverlayedMap<K, V, I> {
 	fn default() -> Self {
 		Self {
 			changes: BTreeMap::new(),
 			dirty_keys: SmallVec::new(),
 			num_client_transactions: Default::default(),
 			execution_mode: Default::default(),
+			base: None,
 		}
 	}
 }
@@ -128,7 +134,7 @@ impl Default for ExecutionMode {
 	}
 }
 
-impl<V> OverlayedEntry<V> {
+impl<V, I: Ord + Copy> OverlayedEntry<V, I> {
 	/// The value as seen by the current transaction.
 	pub fn value_ref(&self) -> &V {
 		&self.transactions.last().expect(PROOF_OVERLAY_NON_EMPTY).value
@@ -140,7 +146,7 @@ impl<V> OverlayedEntry<V> {
 	}
 
 	/// Unique list of extrinsic indices which modified the value.
-	pub fn extrinsics(&self) -> BTreeSet<u32> {
+	pub fn extrinsics(&self) -> BTreeSet<I> {
 		let mut set = BTreeSet::new();
 		self.transactions.iter().for_each(|t| t.extrinsics.copy_extrinsics_into(&mut set));
 		set
@@ -152,12 +158,12 @@ impl<V> OverlayedEntry<V> {
 	}
 
 	/// Remove the last version and return it.
-	fn pop_transaction(&mut self) -> InnerValue<V> {
+	fn pop_transaction(&mut self) -> InnerValue<V, I> {
 		self.transactions.pop().expect(PROOF_OVERLAY_NON_EMPTY)
 	}
 
 	/// Mutable reference to the set which holds the indices for the **current transaction only**.
-	fn transaction_extrinsics_mut(&mut self) -> &mut Extrinsics {
+	fn transaction_extrinsics_mut(&mut self) -> &mut Extrinsics<I> {
 		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).extrinsics
 	}
 
@@ -169,7 +175,7 @@ impl<V> OverlayedEntry<V> {
 		&mut self,
 		value: V,
 		first_write_in_tx: bool,
-		at_extrinsic: Option<u32>,
+		at_extrinsic: Option<I>,
 	) {
 		if first_write_in_tx || self.transactions.is_empty() {
 			self.transactions.push(InnerValue {
@@ -186,7 +192,7 @@ impl<V> OverlayedEntry<V> {
 	}
 }
 
-impl OverlayedEntry<Option<StorageValue>> {
+impl<I: Ord + Copy> OverlayedEntry<Option<StorageValue>, I> {
 	/// The value as seen by the current transaction.
 	pub fn value(&self) -> Option<&StorageValue> {
 		self.value_ref().as_ref()
@@ -201,7 +207,36 @@ fn insert_dirty<K: Ord + Hash>(set: &mut DirtyKeysSets<K>, key: K) -> bool {
 	set.last_mut().map(|dk| dk.insert(key)).unwrap_or_default()
 }
 
-impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
+/// Merge the entries of a map with the entries of its base that the map does not shadow.
+///
+/// Both `local` and `base` must be ordered by key and so is the returned iterator.
+fn with_base_entries<'a, K: Ord, E>(
+	changes: &'a BTreeMap<K, E>,
+	local: impl Iterator<Item = (&'a K, &'a E)>,
+	base: impl Iterator<Item = (&'a K, &'a E)>,
+) -> impl Iterator<Item = (&'a K, &'a E)> {
+	let mut local = local.peekable();
+	let mut base = base.filter(move |(key, _)| !changes.contains_key(*key)).peekable();
+	sp_std::iter::from_fn(move || match (local.peek(), base.peek()) {
+		(Some((local_key, _)), Some((base_key, _))) if base_key < local_key => base.next(),
+		(None, _) => base.next(),
+		_ => local.next(),
+	})
+}
+
+impl<K: Ord + Hash + Clone, V: Clone, I: Ord + Copy> OverlayedMap<K, V, I> {
+	/// Create an empty changeset on top of the committed entries of another one.
+	///
+	/// The entries of `base` are visible through every method reading this changeset as
+	/// long as they are not overwritten. Writes never change `base`, which is why it can be
+	/// shared by several changesets. Use `committed_entries` to create it.
+	pub fn with_base(base: Arc<BTreeMap<K, OverlayedEntry<V, I>>>) -> Self {
+		Self {
+			base: Some(base),
+			..Default::default()
+		}
+	}
+
 	/// Create a new changeset at the same transaction state but without any contents.
 	///
 	/// This changeset might be created when there are already open transactions.
@@ -213,21 +248,107 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 			dirty_keys: repeat(Set::new()).take(self.transaction_depth()).collect(),
 			num_client_transactions: self.num_client_transactions,
 			execution_mode: self.execution_mode,
+			base: None,
 		}
 	}
 
 	/// True if no changes at all are contained in the change set.
 	pub fn is_empty(&self) -> bool {
-		self.changes.is_empty()
+		self.changes.is_empty() && self.base.iter().all(|base| base.is_empty())
 	}
 
 	/// Get an optional reference to the value stored for the specified key.
-	pub fn get<Q>(&self, key: &Q) -> Option<&OverlayedEntry<V>>
+	pub fn get<Q>(&self, key: &Q) -> Option<&OverlayedEntry<V, I>>
 		where
 			K: sp_std::borrow::Borrow<Q>,
 			Q: Ord + ?Sized, 
 	{
-		self.changes.get(key)
+		self.changes.get(key).or_else(|| self.base.as_ref()?.get(key))
+	}
+
+	/// Get an optional reference to the value committed for the specified key.
+	///
+	/// The committed value is the one that was written outside of any transaction that
+	/// is still open. None is returned if there is no such value.
+	pub fn get_committed<Q>(&self, key: &Q) -> Option<&V>
+		where
+			K: sp_std::borrow::Borrow<Q>,
+			Q: Ord + Hash + ?Sized,
+	{
+		let overlayed = self.get(key)?;
+		self.committed_value(key, overlayed)
+	}
+
+	/// Create a new map holding only the committed values of this one.
+	///
+	/// The new map has no open transactions and is in client execution mode.
+	pub fn clone_committed(&self) -> Self {
+		Self {
+			changes: self.committed_entries(),
+			..Default::default()
+		}
+	}
+
+	/// Get the entries holding the committed values, including the ones of the base.
+	pub fn committed_entries(&self) -> BTreeMap<K, OverlayedEntry<V, I>> {
+		self.changes()
+			.filter(|(key, overlayed)| self.committed_value(*key, overlayed).is_some())
+			.map(|(key, overlayed)| (
+				key.clone(),
+				OverlayedEntry {
+					transactions: overlayed.transactions.iter().take(1).cloned().collect(),
+				},
+			))
+			.collect()
+	}
+
+	/// Get the committed and the current value for the specified key with a single lookup.
+	///
+	/// Returns `(committed, current)` with the same semantics as `get_committed` and `get`.
+	pub fn get_committed_and_current<Q>(&self, key: &Q) -> (Option<&V>, Option<&V>)
+		where
+			K: sp_std::borrow::Borrow<Q>,
+			Q: Ord + Hash + ?Sized,
+	{
+		match self.get(key) {
+			Some(overlayed) => (self.committed_value(key, overlayed), Some(overlayed.value_ref())),
+			None => (None, None),
+		}
+	}
+
+	/// Get a list of all committed changes, ignoring any open transaction.
+	pub fn committed_changes(&self) -> impl Iterator<Item=(&K, &V)> {
+		self.changes().filter_map(move |(key, overlayed)| {
+			self.committed_value(key, overlayed).map(|value| (key, value))
+		})
+	}
+
+	/// Resolve the committed value of an entry stored under `key`.
+	///
+	/// Every open transaction that wrote `key` pushed one version to the entry. If there are
+	/// more versions than that, the first one was committed without any open transaction.
+	fn committed_value<'a, Q>(&self, key: &Q, overlayed: &'a OverlayedEntry<V, I>) -> Option<&'a V>
+		where
+			K: sp_std::borrow::Borrow<Q>,
+			Q: Ord + Hash + ?Sized,
+	{
+		let written_in_tx = self.dirty_keys.iter().filter(|keys| keys.contains(key)).count();
+		if overlayed.transactions.len() > written_in_tx {
+			overlayed.transactions.first().map(|tx| &tx.value)
+		} else {
+			None
+		}
+	}
+
+	/// Get the entry of `key` for writing, starting from its base entry if there is one.
+	fn entry_mut(&mut self, key: K) -> &mut OverlayedEntry<V, I> {
+		match self.changes.entry(key) {
+			Entry::Occupied(entry) => entry.into_mut(),
+			Entry::Vacant(entry) => {
+				let base = self.base.as_ref().and_then(|base| base.get(entry.key()));
+				entry.insert(base.cloned().unwrap_or_default())
+			},
+		}
 	}
 
 	/// Set a new value for the specified key.
@@ -237,30 +358,126 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 		&mut self,
 		key: K,
 		value: V,
-		at_extrinsic: Option<u32>,
+		at_extrinsic: Option<I>,
 	) {
-		let overlayed = self.changes.entry(key.clone()).or_default();
-		overlayed.set(value, insert_dirty(&mut self.dirty_keys, key), at_extrinsic);
+		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key.clone());
+		self.entry_mut(key).set(value, first_write_in_tx, at_extrinsic);
+	}
+
+	/// Set a new value for the specified borrowed key.
+	///
+	/// Unlike `set` the key is only cloned when it is not yet part of the changeset or
+	/// the current transaction.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_ref<Q>(
+		&mut self,
+		key: &Q,
+		value: V,
+		at_extrinsic: Option<I>,
+	) where
+		K: sp_std::borrow::Borrow<Q>,
+		Q: Ord + Hash + ToOwned<Owned = K> + ?Sized,
+	{
+		let first_write_in_tx = match self.dirty_keys.last_mut() {
+			Some(dirty_keys) if !dirty_keys.contains(key) => dirty_keys.insert(key.to_owned()),
+			_ => false,
+		};
+		match self.changes.get_mut(key) {
+			Some(overlayed) => overlayed.set(value, first_write_in_tx, at_extrinsic),
+			None => self.entry_mut(key.to_owned()).set(value, first_write_in_tx, at_extrinsic),
+		}
+	}
+
+	/// Set a new value for the specified key and replace the extrinsics recorded for it by
+	/// the current transaction with `extrinsics`.
+	///
+	/// Versions of the value written by enclosing transactions keep their extrinsics.
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_with_extrinsics(
+		&mut self,
+		key: K,
+		value: V,
+		extrinsics: BTreeSet<I>,
+	) {
+		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key.clone());
+		let overlayed = self.entry_mut(key);
+		overlayed.set(value, first_write_in_tx, None);
+		overlayed.transaction_extrinsics_mut().replace(extrinsics);
 	}
 
 	/// Get a list of all changes as seen by current transaction.
-	pub fn changes(&self) -> impl Iterator<Item=(&K, &OverlayedEntry<V>)> {
-		self.changes.iter()
+	pub fn changes(&self) -> impl Iterator<Item=(&K, &OverlayedEntry<V, I>)> {
+		let base = self.base.iter().flat_map(|base| base.iter());
+		with_base_entries(&self.changes, self.changes.iter(), base)
 	}
 
 	/// Get a list of all changes as seen by current transaction, consumes
 	/// the overlay.
-	pub fn into_changes(self) -> impl Iterator<Item=(K, OverlayedEntry<V>)> {
-		self.changes.into_iter()
+	pub fn into_changes(mut self) -> impl Iterator<Item=(K, OverlayedEntry<V, I>)> {
+		self.take_entries().into_iter()
+	}
+
+	/// Take all entries including the ones of the base, which is dropped.
+	fn take_entries(&mut self) -> BTreeMap<K, OverlayedEntry<V, I>> {
+		let mut changes = sp_std::mem::take(&mut self.changes);
+		for (key, overlayed) in self.base.take().iter().flat_map(|base| base.iter()) {
+			changes.entry(key.clone()).or_insert_with(|| overlayed.clone());
+		}
+		changes
 	}
 
 	/// Consume this changeset and return all committed changes.
 	///
 	/// Panics:
 	/// Panics if there are open transactions: `transaction_depth() > 0`
-	pub fn drain_commited(self) -> impl Iterator<Item=(K, V)> {
+	pub fn drain_commited(mut self) -> impl Iterator<Item=(K, V)> {
 		assert!(self.transaction_depth() == 0, "Drain is not allowed with open transactions.");
-		self.changes.into_iter().map(|(k, mut v)| (k, v.pop_transaction().value))
+		self.take_entries().into_iter().map(|(k, mut v)| (k, v.pop_transaction().value))
+	}
+
+	/// Remove the committed versions of all values and return them.
+	///
+	/// Versions written by transactions that are still open are kept, so only keys that
+	/// were not written by any of them are removed from the changeset. The base is drained
+	/// as well.
+	pub fn drain_committed_only(&mut self) -> Vec<(K, V)> {
+		let mut drained = Vec::new();
+		for (key, mut overlayed) in self.take_entries() {
+			let written_in_tx = self.dirty_keys.iter().filter(|keys| keys.contains(&key)).count();
+			if overlayed.transactions.len() > written_in_tx {
+				let committed = overlayed.transactions.remove(0);
+				drained.push((key.clone(), committed.value));
+			}
+			if !overlayed.transactions.is_empty() {
+				self.changes.insert(key, overlayed);
+			}
+		}
+		drained
+	}
+
+	/// Remove the versions written by open transactions and return the current values.
+	///
+	/// The transactions stay open but no longer hold any changes, which leaves the changeset
+	/// with its committed values only.
+	pub fn drain_uncommitted(&mut self) -> Vec<(K, V)> {
+		let mut drained = Vec::new();
+		for (key, mut overlayed) in sp_std::mem::take(&mut self.changes) {
+			let written_in_tx = self.dirty_keys.iter().filter(|keys| keys.contains(&key)).count();
+			if written_in_tx > 0 {
+				let committed = overlayed.transactions.len() - written_in_tx;
+				let current = overlayed.pop_transaction();
+				overlayed.transactions.truncate(committed);
+				drained.push((key.clone(), current.value));
+			}
+			if !overlayed.transactions.is_empty() {
+				self.changes.insert(key, overlayed);
+			}
+		}
+		for dirty_keys in &mut self.dirty_keys {
+			dirty_keys.clear();
+		}
+		drained
 	}
 
 	/// Returns the current nesting depth of the transaction stack.
@@ -270,6 +487,36 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 		self.dirty_keys.len()
 	}
 
+	/// Returns whether any key was written by a transaction that is still open.
+	pub fn has_uncommitted_changes(&self) -> bool {
+		self.dirty_keys.iter().any(|keys| !keys.is_empty())
+	}
+
+	/// Get all changes that were written by any transaction that is still open.
+	pub fn uncommitted_changes(&self) -> impl Iterator<Item=(&K, &OverlayedEntry<V, I>)> {
+		self.changes.iter()
+			.filter(move |(key, _)| self.dirty_keys.iter().any(|keys| keys.contains(*key)))
+	}
+
+	/// Get the keys written by the most recently started transaction that is still open.
+	///
+	/// Nothing is returned when no transaction is open. The order is unspecified.
+	pub fn transaction_keys(&self) -> impl Iterator<Item=&K> {
+		self.dirty_keys.last().into_iter().flatten()
+	}
+
+	/// Get the keys written by the transactions that `exit_runtime` would roll back.
+	///
+	/// Nothing is returned outside of the runtime. Keys written by several of those
+	/// transactions are returned once per transaction and the order is unspecified.
+	pub fn runtime_transaction_keys(&self) -> impl Iterator<Item=&K> {
+		let first = match self.execution_mode {
+			ExecutionMode::Client => self.dirty_keys.len(),
+			ExecutionMode::Runtime => self.num_client_transactions,
+		};
+		self.dirty_keys[first..].iter().flatten()
+	}
+
 	/// Call this before transfering control to the runtime.
 	///
 	/// This protects all existing transactions from being removed by the runtime.
@@ -285,7 +532,7 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 
 	/// Call this when control returns from the runtime.
 	///
-	/// This commits all dangling transaction left open by the runtime.
+	/// This rolls back all dangling transaction left open by the runtime.
 	/// Calling this while already outside the runtime will return an error.
 	pub fn exit_runtime(&mut self) -> Result<(), NotInRuntime> {
 		if let ExecutionMode::Client = self.execution_mode {
@@ -393,10 +640,10 @@ impl OverlayedChangeSet {
 		&mut self,
 		key: StorageKey,
 		init: impl Fn() -> StorageValue,
-		at_extrinsic: Option<u32>,
+		at_extrinsic: Option<ExtrinsicIndex>,
 	) -> &mut Option<StorageValue> {
-		let overlayed = self.changes.entry(key.clone()).or_default();
-		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key);
+		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key.clone());
+		let overlayed = self.entry_mut(key);
 		let clone_into_new_tx = if let Some(tx) = overlayed.transactions.last() {
 			if first_write_in_tx {
 				Some(tx.value.clone())
@@ -419,18 +666,66 @@ impl OverlayedChangeSet {
 	pub fn clear_where(
 		&mut self,
 		predicate: impl Fn(&[u8], &OverlayedValue) -> bool,
-		at_extrinsic: Option<u32>,
+		at_extrinsic: Option<ExtrinsicIndex>,
 	) {
+		for (key, val) in self.base.iter().flat_map(|base| base.iter()) {
+			if !self.changes.contains_key(key) && predicate(key, val) {
+				self.changes.insert(key.clone(), val.clone());
+			}
+		}
 		for (key, val) in self.changes.iter_mut().filter(|(k, v)| predicate(k, v)) {
 			val.set(None, insert_dirty(&mut self.dirty_keys, key.clone()), at_extrinsic);
 		}
 	}
 
+	/// Get the iterator over all changes starting with the supplied `key`.
+	pub fn changes_from(&self, key: &[u8]) -> impl Iterator<Item = (&[u8], &OverlayedValue)> {
+		use sp_std::ops::Bound;
+		let range = (Bound::Included(key), Bound::Unbounded);
+		let base = self.base.as_ref().map(|base| base.range::<[u8], _>(range));
+		with_base_entries(&self.changes, self.changes.range::<[u8], _>(range), base.into_iter().flatten())
+			.map(|(k, v)| (k.as_slice(), v))
+	}
+
 	/// Get the iterator over all changes that follow the supplied `key`.
 	pub fn changes_after(&self, key: &[u8]) -> impl Iterator<Item = (&[u8], &OverlayedValue)> {
 		use sp_std::ops::Bound;
 		let range = (Bound::Excluded(key), Bound::Unbounded);
-		self.changes.range::<[u8], _>(range).map(|(k, v)| (k.as_slice(), v))
+		let base = self.base.as_ref().map(|base| base.range::<[u8], _>(range));
+		with_base_entries(&self.changes, self.changes.range::<[u8], _>(range), base.into_iter().flatten())
+			.map(|(k, v)| (k.as_slice(), v))
+	}
+
+	/// Check that the versions of every key are consistent with the open transactions.
+	///
+	/// Every open transaction that wrote a key must have pushed exactly one version for it,
+	/// plus at most one committed version below them. Every key marked dirty must be present.
+	#[cfg(feature = "std")]
+	pub fn check_invariants(&self) -> Result<(), String> {
+		for (key, overlayed) in self.changes.iter() {
+			let written_in_tx = self.dirty_keys.iter().filter(|keys| keys.contains(key)).count();
+			let versions = overlayed.transactions.len();
+			if versions == 0 || versions < written_in_tx || versions > written_in_tx + 1 {
+				return Err(format!(
+					"Key {:?} has {} versions but was written by {} open transactions",
+					key, versions, written_in_tx,
+				));
+			}
+		}
+		for (depth, keys) in self.dirty_keys.iter().enumerate() {
+			if let Some(key) = keys.iter().find(|key| !self.changes.contains_key(*key)) {
+				return Err(format!("Key {:?} is dirty in transaction {} but has no value", key, depth));
+			}
+		}
+		if let ExecutionMode::Runtime = self.execution_mode {
+			if self.num_client_transactions > self.transaction_depth() {
+				return Err(format!(
+					"{} client transactions are protected but only {} are open",
+					self.num_client_transactions, self.transaction_depth(),
+				));
+			}
+		}
+		Ok(())
 	}
 }
 
@@ -733,6 +1028,56 @@ mod test {
 
 	}
 
+	#[test]
+	fn transaction_keys_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		assert_eq!(changeset.transaction_keys().count(), 0);
+
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key2".to_vec(), Some(b"val2".to_vec()), None);
+		changeset.set(b"key0".to_vec(), None, None);
+
+		let mut keys: Vec<_> = changeset.transaction_keys().cloned().collect();
+		keys.sort();
+		assert_eq!(keys, vec![b"key0".to_vec(), b"key2".to_vec()]);
+
+		changeset.commit_transaction().unwrap();
+		let mut keys: Vec<_> = changeset.transaction_keys().cloned().collect();
+		keys.sort();
+		assert_eq!(keys, vec![b"key0".to_vec(), b"key1".to_vec(), b"key2".to_vec()]);
+	}
+
+	#[test]
+	fn committed_changes_ignore_open_transactions() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.set(b"key1".to_vec(), None, None);
+
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val0-1".to_vec()), None);
+		changeset.set(b"key2".to_vec(), Some(b"val2".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val0-2".to_vec()), None);
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+
+		assert_eq!(changeset.get_committed(&b"key0"[..]), Some(&Some(b"val0".to_vec())));
+		assert_eq!(changeset.get_committed(&b"key1"[..]), Some(&None));
+		assert_eq!(changeset.get_committed(&b"key2"[..]), None);
+		assert_eq!(changeset.get_committed(&b"key3"[..]), None);
+		assert_eq!(
+			changeset.committed_changes().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>(),
+			vec![(b"key0".to_vec(), Some(b"val0".to_vec())), (b"key1".to_vec(), None)],
+		);
+
+		changeset.commit_transaction().unwrap();
+		changeset.commit_transaction().unwrap();
+		assert_eq!(changeset.get_committed(&b"key0"[..]), Some(&Some(b"val0-2".to_vec())));
+		assert_eq!(changeset.get_committed(&b"key2"[..]), Some(&Some(b"val2".to_vec())));
+	}
+
 	#[test]
 	fn no_open_tx_commit_errors() {
 		let mut changeset = OverlayedChangeSet::default();
@@ -795,6 +1140,59 @@ mod test {
 		]);
 	}
 
+	#[test]
+	fn set_ref_only_clones_new_keys() {
+		use std::cell::Cell;
+
+		thread_local! {
+			static CLONES: Cell<usize> = Cell::new(0);
+		}
+
+		#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+		struct Key(u8);
+
+		impl Clone for Key {
+			fn clone(&self) -> Self {
+				CLONES.with(|c| c.set(c.get() + 1));
+				Key(self.0)
+			}
+		}
+
+		let clones = || CLONES.with(|c| c.replace(0));
+		let mut changeset = OverlayedMap::<Key, u32>::default();
+
+		changeset.set_ref(&Key(1), 1, None);
+		assert_eq!(clones(), 1);
+		changeset.set_ref(&Key(1), 2, None);
+		assert_eq!(clones(), 0);
+
+		changeset.start_transaction();
+		changeset.set_ref(&Key(1), 3, None);
+		assert_eq!(clones(), 1);
+		changeset.set_ref(&Key(1), 4, None);
+		assert_eq!(clones(), 0);
+		changeset.rollback_transaction().unwrap();
+		assert_eq!(changeset.get(&Key(1)).map(|v| *v.value_ref()), Some(2));
+	}
+
+	#[test]
+	fn wide_extrinsic_indices_are_tracked() {
+		let big = u64::from(u32::MAX) + 7;
+		let mut changeset = OverlayedMap::<StorageKey, Option<StorageValue>, u64>::default();
+
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(big));
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val1".to_vec()), Some(1));
+		changeset.set_with_extrinsics(b"key1".to_vec(), None, vec![big + 1].into_iter().collect());
+		changeset.commit_transaction().unwrap();
+
+		let extrinsics = |key: &[u8]| changeset.get(key).unwrap().extrinsics()
+			.into_iter().collect::<Vec<u64>>();
+		assert_eq!(extrinsics(b"key0"), vec![1, big]);
+		assert_eq!(extrinsics(b"key1"), vec![big + 1]);
+		assert_eq!(changeset.get(&b"key0"[..]).unwrap().value(), Some(&b"val1".to_vec()));
+	}
+
 	#[test]
 	fn enter_exit_runtime_fails_when_already_in_requested_mode() {
 		let mut changeset = OverlayedChangeSet::default();
@@ -805,4 +1203,119 @@ mod test {
 		assert_eq!(changeset.exit_runtime(), Ok(()));
 		assert_eq!(changeset.exit_runtime(), Err(NotInRuntime));
 	}
+
+	#[test]
+	fn check_invariants_detects_inconsistent_versions() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val0-1".to_vec()), None);
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+		assert_eq!(changeset.check_invariants(), Ok(()));
+
+		// A version pushed without marking the key dirty in any transaction.
+		let mut corrupted = changeset.clone();
+		corrupted.changes.get_mut(&b"key0"[..]).unwrap().transactions.push(Default::default());
+		assert!(corrupted.check_invariants().is_err());
+
+		// A key marked dirty without any value.
+		let mut corrupted = changeset.clone();
+		corrupted.dirty_keys[0].insert(b"key2".to_vec());
+		assert!(corrupted.check_invariants().is_err());
+	}
+
+	#[test]
+	fn drain_committed_only_keeps_open_transactions() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(1));
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), Some(1));
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1-1".to_vec()), Some(2));
+		changeset.set(b"key2".to_vec(), None, Some(2));
+
+		assert_eq!(changeset.drain_committed_only(), vec![
+			(b"key0".to_vec(), Some(b"val0".to_vec())),
+			(b"key1".to_vec(), Some(b"val1".to_vec())),
+		]);
+		assert_eq!(changeset.check_invariants(), Ok(()));
+		assert!(changeset.get(&b"key0"[..]).is_none());
+		assert_eq!(changeset.get(&b"key1"[..]).unwrap().value(), Some(&b"val1-1".to_vec()));
+		assert!(changeset.drain_committed_only().is_empty());
+
+		changeset.rollback_transaction().unwrap();
+		assert!(changeset.is_empty());
+	}
+
+	#[test]
+	fn same_transaction_overwrite_keeps_history() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(1));
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val1".to_vec()), Some(2));
+		changeset.set(b"key0".to_vec(), Some(b"val2".to_vec()), Some(3));
+
+		let overlayed = changeset.get(&b"key0"[..]).unwrap();
+		assert_eq!(overlayed.transactions.len(), 2);
+		assert_eq!(overlayed.value(), Some(&b"val2".to_vec()));
+		assert_eq!(overlayed.extrinsics().into_iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+		changeset.rollback_transaction().unwrap();
+		assert_eq!(changeset.get(&b"key0"[..]).unwrap().value(), Some(&b"val0".to_vec()));
+	}
+
+	#[test]
+	fn drain_uncommitted_keeps_committed_values() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(1));
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), Some(1));
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1-1".to_vec()), Some(2));
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1-2".to_vec()), Some(3));
+		changeset.set(b"key2".to_vec(), None, Some(3));
+
+		assert_eq!(changeset.drain_uncommitted(), vec![
+			(b"key1".to_vec(), Some(b"val1-2".to_vec())),
+			(b"key2".to_vec(), None),
+		]);
+		assert_eq!(changeset.check_invariants(), Ok(()));
+		assert_eq!(changeset.transaction_depth(), 2);
+		assert!(!changeset.has_uncommitted_changes());
+		assert!(changeset.drain_uncommitted().is_empty());
+
+		changeset.commit_transaction().unwrap();
+		changeset.commit_transaction().unwrap();
+		assert_drained(changeset, vec![
+			(b"key0", Some(b"val0")),
+			(b"key1", Some(b"val1")),
+		]);
+	}
+
+	#[test]
+	fn base_entries_are_shadowed_and_kept_on_rollback() {
+		let mut base = OverlayedChangeSet::default();
+		base.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(1));
+		base.set(b"key1".to_vec(), Some(b"val1".to_vec()), Some(1));
+		base.start_transaction();
+		base.set(b"key2".to_vec(), Some(b"val2".to_vec()), Some(2));
+
+		let mut changeset = OverlayedChangeSet::with_base(Arc::new(base.committed_entries()));
+		assert!(!changeset.is_empty());
+		assert_eq!(changeset.get(&b"key2"[..]), None);
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), None, Some(3));
+		changeset.clear_where(|key, _| key == b"key0", Some(3));
+		assert_eq!(changeset.check_invariants(), Ok(()));
+		assert_changes(&changeset, &vec![
+			(b"key0", (None, vec![1, 3])),
+			(b"key1", (None, vec![1, 3])),
+		]);
+
+		changeset.rollback_transaction().unwrap();
+		assert_eq!(changeset.committed_changes().count(), 2);
+		assert_drained(changeset, vec![
+			(b"key0", Some(b"val0")),
+			(b"key1", Some(b"val1")),
+		]);
+	}
 }
@@ -25,7 +25,10 @@ use crate::{
 	backend::Backend,
 	stats::StateMachineStats,
 };
-use sp_std::{vec::Vec, any::{TypeId, Any}, boxed::Box};
+use sp_std::{
+	vec::Vec, any::{TypeId, Any}, boxed::Box, borrow::Cow, iter::Peekable, marker::PhantomData,
+	cmp::Ordering, cell::RefCell, sync::Arc,
+};
 use self::changeset::OverlayedChangeSet;
 
 #[cfg(feature = "std")]
@@ -41,9 +44,9 @@ use crate::changes_trie::BlockNumber;
 use std::collections::{HashMap as Map, hash_map::Entry as MapEntry};
 #[cfg(not(feature = "std"))]
 use sp_std::collections::btree_map::{BTreeMap as Map, Entry as MapEntry};
-use sp_std::collections::btree_set::BTreeSet;
+use sp_std::collections::{btree_set::BTreeSet, btree_map::BTreeMap};
 use codec::{Decode, Encode};
-use sp_core::storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo};
+use sp_core::storage::{well_known_keys::{self, EXTRINSIC_INDEX}, ChildInfo};
 use sp_core::offchain::OffchainOverlayedChange;
 use hash_db::Hasher;
 use crate::DefaultError;
@@ -51,8 +54,11 @@ use sp_externalities::{Extensions, Extension};
 
 pub use self::changeset::{OverlayedValue, NoOpenTransaction, AlreadyInRuntime, NotInRuntime};
 
+/// Index of an extrinsic within a block, used to attribute storage changes to extrinsics.
+pub type ExtrinsicIndex = u32;
+
 /// Changes that are made outside of extrinsics are marked with this index;
-pub const NO_EXTRINSIC_INDEX: u32 = 0xffffffff;
+pub const NO_EXTRINSIC_INDEX: ExtrinsicIndex = 0xffffffff;
 
 /// Storage key.
 pub type StorageKey = Vec<u8>;
@@ -66,21 +72,45 @@ pub type StorageCollection = Vec<(StorageKey, Option<StorageValue>)>;
 /// In memory arrays of storage values for multiple child tries.
 pub type ChildStorageCollection = Vec<(StorageKey, StorageCollection)>;
 
+/// In memory array of storage keys alongside the extrinsics that changed them.
+pub type ExtrinsicsCollection = Vec<(StorageKey, BTreeSet<ExtrinsicIndex>)>;
+
 /// In memory array of storage values.
 pub type OffchainChangesCollection = Vec<((Vec<u8>, Vec<u8>), OffchainOverlayedChange)>;
 
+/// A changed storage value, where `None` is a deletion.
+type StorageChange = (StorageKey, Option<StorageValue>);
+
+/// A borrowed changed storage value, where `None` is a deletion.
+type StorageChangeRef<'a> = (&'a [u8], Option<&'a [u8]>);
+
+/// A changed storage value wrapped into [`Cow`]s, where `None` is a deletion.
+type StorageChangeCow<'a> = (Cow<'a, [u8]>, Option<Cow<'a, [u8]>>);
+
+/// The changes `I` of a child alongside its storage key and info.
+type ChildChanges<I> = (StorageKey, (I, ChildInfo));
+
+/// A value as seen by the overlay, `None` if the overlay does not know the key.
+type OverlayedRead<'a> = Option<Option<&'a [u8]>>;
+
 /// Keep trace of extrinsics index for a modified value.
-#[derive(Debug, Default, Eq, PartialEq, Clone)]
-pub struct Extrinsics(Vec<u32>);
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Extrinsics<I = ExtrinsicIndex>(Vec<I>);
 
-impl Extrinsics {
+impl<I> Default for Extrinsics<I> {
+	fn default() -> Self {
+		Self(Vec::new())
+	}
+}
+
+impl<I: Ord + Copy> Extrinsics<I> {
 	/// Extracts extrinsics into a `BTreeSets`.
-	fn copy_extrinsics_into(&self, dest: &mut BTreeSet<u32>) {
+	fn copy_extrinsics_into(&self, dest: &mut BTreeSet<I>) {
 		dest.extend(self.0.iter())
 	}
 
 	/// Add an extrinsics.
-	fn insert(&mut self, ext: u32) {
+	fn insert(&mut self, ext: I) {
 		if Some(&ext) != self.0.last() {
 			self.0.push(ext);
 		}
@@ -90,6 +120,11 @@ impl Extrinsics {
 	fn extend(&mut self, other: Self) {
 		self.0.extend(other.0.into_iter());
 	}
+
+	/// Replace all contained extrinsics with the ones from `set`.
+	fn replace(&mut self, set: BTreeSet<I>) {
+		self.0 = set.into_iter().collect();
+	}
 }
 
 /// The set of changes that are overlaid onto the backend.
@@ -107,8 +142,367 @@ pub struct OverlayedChanges {
 	transaction_index_ops: Vec<IndexOperation>,
 	/// True if extrinsics stats must be collected.
 	collect_extrinsics: bool,
+	/// True if collection of extrinsics is temporarily suspended.
+	extrinsic_tracking_suspended: bool,
+	/// Maximum number of live keys per child storage key, enforced by `try_set_child_storage`.
+	child_key_limits: Map<StorageKey, usize>,
+	/// Number of live keys of children with a key limit, once counted by
+	/// `try_set_child_storage`. Dropped whenever it can not be updated cheaply.
+	child_live_keys: BTreeMap<StorageKey, usize>,
 	/// Collect statistic on this execution.
 	stats: StateMachineStats,
+	/// Deepest transaction nesting reached since construction or the last `reset_stats`.
+	max_transaction_depth: usize,
+	/// Mutating operations recorded since `start_recording`, if recording.
+	recording: Option<Vec<OverlayOp>>,
+	/// Top key handed out by `value_mut_or_insert_with` while recording, whose final value
+	/// is recorded before the next operation.
+	pending_value_mut: Option<StorageKey>,
+	/// True if writing an empty value deletes the key.
+	treat_empty_as_deleted: bool,
+	/// Sorted top changes as returned by the last `sorted_top_changes_incremental` call.
+	sorted_top: SortedTopCache,
+	/// True if `write_generation` and `top_generations` are maintained.
+	track_write_generations: bool,
+	/// Incremented by every operation that changes top level values.
+	write_generation: u64,
+	/// The write generation at which each top key was last changed.
+	top_generations: BTreeMap<StorageKey, u64>,
+	/// True if a malformed extrinsic index must not be replaced by `NO_EXTRINSIC_INDEX`.
+	strict_extrinsic_decoding: bool,
+	/// First decoding error of a malformed extrinsic index found by a write in strict mode.
+	extrinsic_index_error: RefCell<Option<codec::Error>>,
+	/// Called with the top keys reverted by `rollback_transaction`.
+	rollback_hook: RollbackHookSlot,
+	/// Tags attached to top keys by `tag_key`, only kept while the overlay holds the key.
+	key_tags: BTreeMap<StorageKey, u8>,
+	/// Receives a record of the changes committed by the outermost `commit_transaction` and
+	/// by writes made while no transaction is open.
+	#[cfg(feature = "std")]
+	commit_log: CommitLog,
+	/// Backend values read by `resolve_and_cache`. They are never part of the changes.
+	backend_reads: BTreeMap<StorageKey, Option<StorageValue>>,
+	/// Transaction depth and token id of every open transaction started by
+	/// `start_transaction_scoped`, innermost last.
+	scoped_transactions: Vec<(usize, u64)>,
+	/// Id of the next token handed out by `start_transaction_scoped`.
+	next_scope_id: u64,
+	/// Storage keys of the children cleared as a whole, with the transaction depth at which
+	/// they were cleared.
+	child_clear_depths: BTreeMap<StorageKey, usize>,
+	/// True if clearing a child without changes must not create an empty entry for it.
+	skip_clear_of_missing_child: bool,
+}
+
+/// Identifies a transaction started by [`OverlayedChanges::start_transaction_scoped`].
+///
+/// The token must be handed back to close the transaction, which can only be done while
+/// it is the innermost open transaction.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TxToken(u64);
+
+/// Writer for the records of committed changes set by `set_commit_log`.
+///
+/// A writer can not be cloned, so a cloned overlay starts without one.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct CommitLog {
+	writer: Option<Box<dyn std::io::Write + Send>>,
+	/// The first error returned by `writer`, no more records are written while it is set.
+	error: Option<std::io::Error>,
+	/// Top keys committed right away because no transaction was open, not yet written.
+	unlogged_top: BTreeSet<StorageKey>,
+	/// Child keys per child storage key committed right away, not yet written.
+	unlogged_children: BTreeMap<StorageKey, BTreeSet<StorageKey>>,
+}
+
+/// Sorted top keys and sorted keys per child storage key written by a transaction.
+#[cfg(feature = "std")]
+type CommittedKeys = (Vec<StorageKey>, Vec<(StorageKey, Vec<StorageKey>)>);
+
+#[cfg(feature = "std")]
+impl CommitLog {
+	/// Whether the next record should be written.
+	fn is_active(&self) -> bool {
+		self.writer.is_some() && self.error.is_none()
+	}
+}
+
+#[cfg(feature = "std")]
+impl Clone for CommitLog {
+	fn clone(&self) -> Self {
+		Self::default()
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Debug for CommitLog {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("CommitLog")
+			.field("active", &self.writer.is_some())
+			.field("error", &self.error)
+			.finish()
+	}
+}
+
+/// Sorted top changes kept by `sorted_top_changes_incremental`.
+///
+/// Cloning a cache yields an inactive one, so a cloned overlay rebuilds it on first use.
+#[derive(Debug, Default)]
+struct SortedTopCache {
+	/// True once `entries` were built, from then on changed top keys are added to `dirty`.
+	active: bool,
+	entries: Vec<(StorageKey, Option<StorageValue>)>,
+	/// Indices into `entries` of the entries that changed with the last refresh.
+	changed: Vec<usize>,
+	/// Top keys whose value may have changed since `entries` were refreshed.
+	dirty: BTreeSet<StorageKey>,
+}
+
+impl Clone for SortedTopCache {
+	fn clone(&self) -> Self {
+		Self::default()
+	}
+}
+
+/// Callback that receives the top keys reverted by rolling back a transaction.
+pub type RollbackHook = Box<dyn FnMut(&[StorageKey]) + Send>;
+
+/// Holds the optional rollback hook of an overlay.
+///
+/// A hook can not be cloned, so a cloned overlay starts without one.
+#[derive(Default)]
+struct RollbackHookSlot(Option<RollbackHook>);
+
+impl Clone for RollbackHookSlot {
+	fn clone(&self) -> Self {
+		Self(None)
+	}
+}
+
+impl sp_std::fmt::Debug for RollbackHookSlot {
+	fn fmt(&self, f: &mut sp_std::fmt::Formatter<'_>) -> sp_std::fmt::Result {
+		match self.0 {
+			Some(_) => write!(f, "Some(RollbackHook)"),
+			None => write!(f, "None"),
+		}
+	}
+}
+
+/// A mutating operation on [`OverlayedChanges`], as recorded by
+/// [`OverlayedChanges::start_recording`].
+#[derive(Debug, Clone)]
+pub enum OverlayOp {
+	/// Enable or disable the collection of extrinsics.
+	SetCollectExtrinsics(bool),
+	/// Enable or disable treating empty values as deletions.
+	SetTreatEmptyAsDeleted(bool),
+	/// Set or delete a top level value.
+	SetStorage {
+		/// Storage key.
+		key: StorageKey,
+		/// New value, `None` for a deletion.
+		value: Option<StorageValue>,
+	},
+	/// Set or delete a top level value attributed to the given extrinsics.
+	SetStorageWithExtrinsics {
+		/// Storage key.
+		key: StorageKey,
+		/// New value, `None` for a deletion.
+		value: Option<StorageValue>,
+		/// Extrinsics that changed the value.
+		extrinsics: BTreeSet<ExtrinsicIndex>,
+	},
+	/// Set or delete a child value.
+	SetChildStorage {
+		/// The child trie.
+		child_info: ChildInfo,
+		/// Storage key within the child.
+		key: StorageKey,
+		/// New value, `None` for a deletion.
+		value: Option<StorageValue>,
+	},
+	/// Delete all values of a child.
+	ClearChildStorage {
+		/// The child trie.
+		child_info: ChildInfo,
+	},
+	/// Delete all top level values under a prefix.
+	ClearPrefix {
+		/// Prefix of the deleted keys.
+		prefix: Vec<u8>,
+	},
+	/// Delete all child values under a prefix.
+	ClearChildPrefix {
+		/// The child trie.
+		child_info: ChildInfo,
+		/// Prefix of the deleted keys.
+		prefix: Vec<u8>,
+	},
+	/// Start a transaction.
+	StartTransaction,
+	/// Commit the last transaction.
+	CommitTransaction,
+	/// Rollback the last transaction.
+	RollbackTransaction,
+	/// Switch to runtime execution mode.
+	EnterRuntime,
+	/// Leave runtime execution mode.
+	ExitRuntime,
+	/// Reset the open transactions to the committed top values.
+	DrainProspective,
+	/// Set a top level value changed in place through
+	/// [`OverlayedChanges::value_mut_or_insert_with`].
+	ModifyStorage {
+		/// Storage key.
+		key: StorageKey,
+		/// Value after the change.
+		value: StorageValue,
+	},
+	/// Enable or disable strict extrinsic index decoding.
+	SetStrictExtrinsicDecoding(bool),
+	/// Enable or disable creating missing children when clearing them.
+	SetCreateMissingChildOnClear(bool),
+	/// Stop recording extrinsic indices.
+	SuspendExtrinsicTracking,
+	/// Resume recording extrinsic indices.
+	ResumeExtrinsicTracking,
+	/// Take the committed changes of a child.
+	DrainChildCommitted {
+		/// Storage key of the child.
+		storage_key: StorageKey,
+	},
+	/// Take all committed changes, as done by [`OverlayedChanges::drain_storage_changes`].
+	DrainCommitted,
+	/// Take the offchain changes.
+	OffchainDrainCommitted,
+	/// Take all committed changes and reset the overlay.
+	TakeAndReset,
+	/// Set or delete an offchain value.
+	SetOffchainStorage {
+		/// Offchain storage key.
+		key: Vec<u8>,
+		/// New value, `None` for a deletion.
+		value: Option<Vec<u8>>,
+	},
+	/// Add a transaction index operation.
+	AddTransactionIndex(IndexOperation),
+}
+
+/// Describes what should happen with a transaction opened by
+/// [`OverlayedChanges::with_transaction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionOutcome<R> {
+	/// Commit the transaction.
+	Commit(R),
+	/// Rollback the transaction.
+	Rollback(R),
+}
+
+impl<R> TransactionOutcome<R> {
+	/// Convert into the inner type.
+	pub fn into_inner(self) -> R {
+		match self {
+			Self::Commit(r) => r,
+			Self::Rollback(r) => r,
+		}
+	}
+}
+
+/// The state of a key as seen by the overlay, see [`OverlayedChanges::storage_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayState<'a> {
+	/// The overlay holds no change for the key, the backend value applies.
+	Unknown,
+	/// The key is deleted by the overlay.
+	Deleted,
+	/// The key holds the given value in the overlay.
+	Present(&'a [u8]),
+}
+
+impl<'a> From<Option<Option<&'a [u8]>>> for OverlayState<'a> {
+	fn from(value: Option<Option<&'a [u8]>>) -> Self {
+		match value {
+			None => OverlayState::Unknown,
+			Some(None) => OverlayState::Deleted,
+			Some(Some(value)) => OverlayState::Present(value),
+		}
+	}
+}
+
+/// Number of overlayed values by kind, see [`OverlayedChanges::changes_summary`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChangesSummary {
+	/// Top level keys that hold a value.
+	pub sets: usize,
+	/// Top level keys that are deleted.
+	pub deletes: usize,
+	/// Child keys that hold a value, across all children.
+	pub child_sets: usize,
+	/// Child keys that are deleted, across all children.
+	pub child_deletes: usize,
+}
+
+/// Error returned by [`OverlayedChanges::try_set_child_storage`].
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub enum ChildWriteError {
+	/// The child storage key is rejected by [`validate_child_keyspace`].
+	InvalidKeyspace(InvalidChildKeyspace),
+	/// The write would make the child trie hold more keys than allowed by
+	/// [`OverlayedChanges::set_child_max_keys`].
+	KeyLimitExceeded,
+}
+
+impl From<InvalidChildKeyspace> for ChildWriteError {
+	fn from(err: InvalidChildKeyspace) -> Self {
+		ChildWriteError::InvalidKeyspace(err)
+	}
+}
+
+/// Error returned when closing a transaction by its [`TxToken`].
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub enum TxScopeError {
+	/// There is no open transaction.
+	NoOpenTransaction,
+	/// The token does not belong to the innermost open transaction.
+	TokenMismatch,
+}
+
+/// Error returned by [`OverlayedChanges::try_clear_child_storage`] when the overlay holds
+/// no changes for the given child storage key.
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct UnknownChild;
+
+/// Error returned by [`OverlayedChanges::set_child_storage_value`] and
+/// [`validate_child_keyspace`] when the given storage key cannot name a child trie.
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct InvalidChildKeyspace;
+
+/// A mutable storage that committed changes of an [`OverlayedChanges`] can be written to.
+pub trait BackendMut {
+	/// Insert or overwrite the value stored under `key`.
+	fn insert(&mut self, key: StorageKey, value: StorageValue);
+
+	/// Remove the value stored under `key`.
+	fn remove(&mut self, key: &[u8]);
+
+	/// Insert or overwrite the value stored under `key` in the given child trie.
+	fn insert_child(&mut self, child_info: &ChildInfo, key: StorageKey, value: StorageValue);
+
+	/// Remove the value stored under `key` in the given child trie.
+	fn remove_child(&mut self, child_info: &ChildInfo, key: &[u8]);
+}
+
+/// A storage that can be iterated in key order, used by [`OverlayedChanges::merged_iter`].
+pub trait BackendIter {
+	/// Iterate over all key-value pairs whose key starts with `prefix`, in lexicographic order
+	/// of the keys.
+	fn iter_prefix<'a>(&'a self, prefix: &[u8])
+		-> Box<dyn Iterator<Item = (StorageKey, StorageValue)> + 'a>;
 }
 
 /// Transcation index operation.
@@ -132,6 +526,16 @@ pub enum IndexOperation {
 	}
 }
 
+/// Extrinsic attribution of the overlayed changes, as consumed by the changes trie.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangesTrieInput {
+	/// Top level keys alongside the indices of the extrinsics that changed them.
+	pub top: ExtrinsicsCollection,
+	/// Child keys alongside the indices of the extrinsics that changed them,
+	/// grouped by child storage key.
+	pub children: Vec<(StorageKey, ExtrinsicsCollection)>,
+}
+
 /// A storage changes structure that can be generated by the data collected in [`OverlayedChanges`].
 ///
 /// This contains all the changes to the storage and transactions to apply theses changes to the
@@ -250,16 +654,308 @@ impl<Transaction: Default, H: Hasher, N: BlockNumber> Default for StorageChanges
 }
 
 impl OverlayedChanges {
+	/// Create an empty overlay that does not collect extrinsics.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Create an empty overlay on top of the committed changes of `base`.
+	///
+	/// The committed top and child values of `base` are taken once into shared snapshots
+	/// and are visible through every read of the new overlay as if they were committed to
+	/// it, including iteration, the storage root and draining. Writes only go to the new
+	/// overlay and never change `base`. Clones of the new overlay share the snapshots, so
+	/// further forks of the same base are cheap. The new overlay is configured like `base`.
+	pub fn with_base(base: &OverlayedChanges) -> Self {
+		let children = base.children.iter()
+			.map(|(storage_key, (changeset, info))| (storage_key, changeset.committed_entries(), info))
+			.filter(|(_, committed, _)| !committed.is_empty())
+			.map(|(storage_key, committed, info)| (
+				storage_key.clone(),
+				(OverlayedChangeSet::with_base(Arc::new(committed)), info.clone()),
+			))
+			.collect();
+		Self {
+			top: OverlayedChangeSet::with_base(Arc::new(base.top.committed_entries())),
+			children,
+			..base.empty_with_config()
+		}
+	}
+
+	/// Create an empty overlay that collects extrinsics from the start.
+	///
+	/// This is needed for the overlay to be used to build a changes trie.
+	pub fn new_collecting_extrinsics() -> Self {
+		let mut overlay = Self::new();
+		overlay.set_collect_extrinsics(true);
+		overlay
+	}
+
 	/// Whether no changes are contained in the top nor in any of the child changes.
 	pub fn is_empty(&self) -> bool {
 		self.top.is_empty() && self.children.is_empty()
 	}
 
+	/// Returns whether any top or child key was written inside a currently open transaction.
+	///
+	/// When this is `false` committing the open transactions does not change anything.
+	pub fn has_prospective_changes(&self) -> bool {
+		self.top.has_uncommitted_changes() ||
+			self.children.values().any(|(changeset, _)| changeset.has_uncommitted_changes())
+	}
+
+	/// Apply the changes of `other` that are not yet committed onto `self`.
+	///
+	/// Every top and child key written by a transaction that is still open in `other` is
+	/// set to its value as seen by `other`'s current transaction. The writes are made in
+	/// `self`'s current transaction and overwrite any value `self` held for those keys.
+	pub fn merge_prospective(&mut self, other: &OverlayedChanges) {
+		for (key, value) in other.top.uncommitted_changes() {
+			self.set_storage(key.clone(), value.value().cloned());
+		}
+		for (changeset, child_info) in other.children.values() {
+			for (key, value) in changeset.uncommitted_changes() {
+				self.set_child_storage(child_info, key.clone(), value.value().cloned());
+			}
+		}
+	}
+
+	/// Take the top changes that are not yet committed.
+	///
+	/// Returns every top key written by a transaction that is still open with its value as
+	/// seen by the current transaction. The open transactions stay open but are reset to the
+	/// committed top values. Children are not touched.
+	pub fn drain_prospective(&mut self) -> Vec<(StorageKey, Option<StorageValue>)> {
+		self.flush_value_mut();
+		let drained = self.top.drain_uncommitted();
+		self.record(|| OverlayOp::DrainProspective);
+		self.note_top_changes(drained.iter().map(|(key, _)| key));
+		for (key, _) in &drained {
+			if self.top.get(key).is_none() {
+				self.key_tags.remove(key);
+			}
+		}
+		drained
+	}
+
 	/// Ask to collect/not to collect extrinsics indices where key(s) has been changed.
 	pub fn set_collect_extrinsics(&mut self, collect_extrinsics: bool) {
+		self.record(|| OverlayOp::SetCollectExtrinsics(collect_extrinsics));
 		self.collect_extrinsics = collect_extrinsics;
 	}
 
+	/// Ask to treat writes of an empty value as deletions.
+	///
+	/// When enabled, `set_storage(key, Some(vec![]))` and the child equivalent behave like
+	/// writing `None`, so the empty value also shows up as a deletion in the drained
+	/// committed changes. Values written before enabling are not changed.
+	pub fn set_treat_empty_as_deleted(&mut self, treat_empty_as_deleted: bool) {
+		self.record(|| OverlayOp::SetTreatEmptyAsDeleted(treat_empty_as_deleted));
+		self.treat_empty_as_deleted = treat_empty_as_deleted;
+	}
+
+	/// Ask to reject a malformed extrinsic index instead of using `NO_EXTRINSIC_INDEX`.
+	///
+	/// When enabled and extrinsics are collected, a write to the overlay that finds a value
+	/// under `EXTRINSIC_INDEX` that can not be decoded records the decoding error, which can
+	/// be retrieved with `take_extrinsic_index_error`. The write itself still succeeds and is
+	/// attributed to `NO_EXTRINSIC_INDEX`. This is meant to catch runtimes that corrupt the
+	/// extrinsic index.
+	pub fn set_strict_extrinsic_decoding(&mut self, strict: bool) {
+		self.record(|| OverlayOp::SetStrictExtrinsicDecoding(strict));
+		self.strict_extrinsic_decoding = strict;
+	}
+
+	/// Take the first error recorded by a write that found a malformed extrinsic index.
+	///
+	/// Errors are only recorded when strict extrinsic decoding is enabled.
+	pub fn take_extrinsic_index_error(&mut self) -> Option<codec::Error> {
+		self.extrinsic_index_error.get_mut().take()
+	}
+
+	/// Ask to create an empty entry when clearing a child that has no changes.
+	///
+	/// This is enabled by default. When disabled, `clear_child_storage` and
+	/// `clear_child_prefix` do nothing for a child the overlay holds no changes for.
+	pub fn set_create_missing_child_on_clear(&mut self, create: bool) {
+		self.record(|| OverlayOp::SetCreateMissingChildOnClear(create));
+		self.skip_clear_of_missing_child = !create;
+	}
+
+	/// Turn an empty value into a deletion if requested by `treat_empty_as_deleted`.
+	fn normalize_value(&self, val: Option<StorageValue>) -> Option<StorageValue> {
+		match val {
+			Some(val) if self.treat_empty_as_deleted && val.is_empty() => None,
+			val => val,
+		}
+	}
+
+	/// Stop recording extrinsic indices for subsequent writes.
+	///
+	/// Writes made while suspended are not attributed to any extrinsic even when extrinsics
+	/// are collected. Use `resume_extrinsic_tracking` to record them again.
+	pub fn suspend_extrinsic_tracking(&mut self) {
+		self.record(|| OverlayOp::SuspendExtrinsicTracking);
+		self.extrinsic_tracking_suspended = true;
+	}
+
+	/// Resume recording extrinsic indices after `suspend_extrinsic_tracking`.
+	pub fn resume_extrinsic_tracking(&mut self) {
+		self.record(|| OverlayOp::ResumeExtrinsicTracking);
+		self.extrinsic_tracking_suspended = false;
+	}
+
+	/// Run `f` with extrinsic tracking suspended.
+	///
+	/// Writes made by `f` are not attributed to any extrinsic. Tracking is resumed afterwards
+	/// unless it was already suspended, also when `f` panics.
+	pub fn without_changes_trie<R, F: FnOnce(&mut Self) -> R>(&mut self, f: F) -> R {
+		let suspended = self.extrinsic_tracking_suspended;
+		self.suspend_extrinsic_tracking();
+
+		#[cfg(feature = "std")]
+		let result = {
+			use std::panic::{catch_unwind, AssertUnwindSafe};
+			catch_unwind(AssertUnwindSafe(|| f(self)))
+		};
+		#[cfg(not(feature = "std"))]
+		let result = f(self);
+
+		if !suspended {
+			self.resume_extrinsic_tracking();
+		}
+		#[cfg(feature = "std")]
+		let result = result.unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+		result
+	}
+
+	/// Returns the value of the given key as seen through the overlay on top of `backend`.
+	///
+	/// The overlayed value is returned if the overlay knows the key, where a deletion
+	/// yields `None`. Otherwise the value is read from `backend`.
+	pub fn resolve<H: Hasher, B: Backend<H>>(
+		&self,
+		backend: &B,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, B::Error> {
+		match self.storage(key) {
+			Some(value) => Ok(value.map(<[u8]>::to_vec)),
+			None => backend.storage(key),
+		}
+	}
+
+	/// Same as [`Self::resolve`], but remembers values read from `backend`.
+	///
+	/// Later calls for the same key are answered without asking the backend again, as long
+	/// as the overlay does not know the key. Remembered values are not changes and are never
+	/// committed. They are forgotten when the committed changes are drained, since the
+	/// backend is expected to change afterwards.
+	pub fn resolve_and_cache<H: Hasher, B: Backend<H>>(
+		&mut self,
+		backend: &B,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, B::Error> {
+		if let Some(value) = self.storage(key) {
+			return Ok(value.map(<[u8]>::to_vec));
+		}
+		if let Some(value) = self.backend_reads.get(key) {
+			return Ok(value.clone());
+		}
+		let value = backend.storage(key)?;
+		self.backend_reads.insert(key.to_vec(), value.clone());
+		Ok(value)
+	}
+
+	/// Returns the length of the value of the given key, using the double-Option of
+	/// [`Self::storage`].
+	pub fn storage_len(&self, key: &[u8]) -> Option<Option<usize>> {
+		self.storage(key).map(|value| value.map(<[u8]>::len))
+	}
+
+	/// Attach a tag to the given top key, replacing any previous tag.
+	///
+	/// Tags are metadata for tooling and never end up in the committed changes. A tag is
+	/// dropped together with the key once the overlay no longer holds it, e.g. because the
+	/// transaction that wrote it was rolled back. Tagging a key unknown to the overlay has
+	/// no effect.
+	pub fn tag_key(&mut self, key: &[u8], tag: u8) {
+		if self.top.get(key).is_some() {
+			self.key_tags.insert(key.to_vec(), tag);
+		}
+	}
+
+	/// Get the tag attached to the given top key by [`Self::tag_key`].
+	pub fn key_tag(&self, key: &[u8]) -> Option<u8> {
+		self.key_tags.get(key).copied()
+	}
+
+	/// Returns whether the given key is deleted in the overlay.
+	///
+	/// Keys unknown to the overlay are not considered deleted.
+	pub fn is_deleted(&self, key: &[u8]) -> bool {
+		matches!(self.storage(key), Some(None))
+	}
+
+	/// Returns whether the given key holds a value in the overlay.
+	///
+	/// Keys unknown to the overlay are not considered present.
+	pub fn is_present(&self, key: &[u8]) -> bool {
+		matches!(self.storage(key), Some(Some(_)))
+	}
+
+	/// Returns the value of the given key, writing the value returned by `default` first if
+	/// the key is unknown to the overlay or deleted.
+	///
+	/// The backend is not consulted. An empty default that is treated as a deletion is
+	/// returned as an empty slice.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn get_or_insert_with<F: FnOnce() -> StorageValue>(
+		&mut self,
+		key: StorageKey,
+		default: F,
+	) -> &[u8] {
+		if !self.is_present(&key) {
+			self.set_storage(key.clone(), Some(default()));
+		}
+		self.storage(&key).flatten().unwrap_or_default()
+	}
+
+	/// Returns whether the value of the given key equals `candidate`.
+	///
+	/// A `candidate` of `None` matches a deleted key. Returns `None` if the key is unknown
+	/// to the overlay.
+	pub fn value_equals(&self, key: &[u8], candidate: Option<&[u8]>) -> Option<bool> {
+		self.storage(key).map(|value| value == candidate)
+	}
+
+	/// Returns the keys whose value length changed since the last commit, with the delta in bytes.
+	///
+	/// Only top keys written by an open transaction are considered. A key without a committed
+	/// value in the overlay counts as empty, as does a deleted key.
+	pub fn size_deltas(&self) -> impl Iterator<Item=(&[u8], isize)> {
+		fn len(value: Option<&Option<StorageValue>>) -> isize {
+			value.and_then(Option::as_ref).map_or(0, |value| value.len() as isize)
+		}
+
+		self.top.uncommitted_changes().filter_map(move |(key, _)| {
+			let (committed, current) = self.top.get_committed_and_current(key);
+			let delta = len(current) - len(committed);
+			if delta != 0 {
+				Some((key.as_slice(), delta))
+			} else {
+				None
+			}
+		})
+	}
+
+	/// Returns the state of the given key in the overlay.
+	///
+	/// This is the same as [`Self::storage`] with the double-Option spelled out.
+	pub fn storage_state(&self, key: &[u8]) -> OverlayState<'_> {
+		self.storage(key).into()
+	}
+
 	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
 	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
 	/// value has been set.
@@ -272,6 +968,30 @@ impl OverlayedChanges {
 		})
 	}
 
+	/// Returns the committed and the current value of `key` as a tuple `(committed, current)`.
+	///
+	/// Both elements are double-Options with the same semantics as [`storage`](Self::storage).
+	/// The committed value ignores all changes made in transactions that are still open.
+	pub fn storage_committed_and_current(
+		&self,
+		key: &[u8],
+	) -> (OverlayedRead<'_>, OverlayedRead<'_>) {
+		let (committed, current) = self.top.get_committed_and_current(key);
+		let current = current.map(|value| {
+			let size_read = value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+			self.stats.tally_read_modified(size_read);
+			value.as_deref()
+		});
+		(committed.map(Option::as_deref), current)
+	}
+
+	/// Returns a double-Option of the hash of the value stored under `key`, with the same
+	/// semantics as [`storage`](Self::storage): None if the key is unknown, Some(None) if
+	/// it has been deleted and Some(Some(hash)) for a key whose value has been set.
+	pub fn storage_hash<H: Hasher>(&self, key: &[u8]) -> Option<Option<H::Out>> {
+		self.storage(key).map(|value| value.map(H::hash))
+	}
+
 	/// Returns mutable reference to current value.
 	/// If there is no value in the overlay, the given callback is used to initiate the value.
 	/// Warning this function registers a change, so the mutable reference MUST be modified.
@@ -283,6 +1003,13 @@ impl OverlayedChanges {
 		key: &[u8],
 		init: impl Fn() -> StorageValue,
 	) -> &mut StorageValue {
+		self.flush_value_mut();
+		if self.recording.is_some() {
+			self.pending_value_mut = Some(key.to_vec());
+		}
+		self.note_top_changes(Some(key));
+		#[cfg(feature = "std")]
+		self.note_immediate_commits(None, Some(key));
 		let value = self.top.modify(key.to_vec(), init, self.extrinsic_index());
 
 		// if the value was deleted initialise it back with an empty vec
@@ -293,39 +1020,212 @@ impl OverlayedChanges {
 	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
 	/// value has been set.
 	pub fn child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Option<&[u8]>> {
-		let map = self.children.get(child_info.storage_key())?;
+		self.child_storage_by_key(child_info.storage_key(), key)
+	}
+
+	/// Returns the state of the given key in the child with the given storage key.
+	///
+	/// This is the same as [`Self::child_storage`] with the double-Option spelled out.
+	pub fn child_storage_state(&self, storage_key: &[u8], key: &[u8]) -> OverlayState<'_> {
+		self.child_storage_by_key(storage_key, key).into()
+	}
+
+	/// Same as [`Self::child_storage`] but with the child identified by its storage key.
+	fn child_storage_by_key(&self, storage_key: &[u8], key: &[u8]) -> Option<Option<&[u8]>> {
+		let map = self.children.get(storage_key)?;
 		let value = map.0.get(key)?.value();
 		let size_read = value.map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_read_modified(size_read);
 		Some(value.map(AsRef::as_ref))
 	}
 
+	/// Returns the committed value of `key` in the child with the given storage key.
+	///
+	/// Same double-Option semantics as [`Self::child_storage`], but all changes made in
+	/// transactions that are still open are ignored.
+	pub fn committed_child_storage(&self, storage_key: &[u8], key: &[u8]) -> Option<Option<&[u8]>> {
+		let (changeset, _) = self.children.get(storage_key)?;
+		changeset.get_committed(key).map(Option::as_deref)
+	}
+
 	/// Set a new value for the specified key.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
 	pub fn set_storage(&mut self, key: StorageKey, val: Option<StorageValue>) {
+		self.record(|| OverlayOp::SetStorage { key: key.clone(), value: val.clone() });
 		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_write_overlay(size_write);
+		let val = self.normalize_value(val);
+		self.note_top_changes(Some(&key));
+		#[cfg(feature = "std")]
+		self.note_immediate_commits(None, Some(&key));
 		self.top.set(key, val, self.extrinsic_index());
 	}
 
-	/// Set a new value for the specified key and child.
+	/// Copy the value of the key `from` to the key `to`.
 	///
-	/// `None` can be used to delete a value specified by the given key.
+	/// Returns `false` without writing anything if the overlay holds no value for `from`,
+	/// either because the key is unknown to the overlay or because it is deleted.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
-	pub(crate) fn set_child_storage(
+	pub fn copy_storage(&mut self, from: &[u8], to: StorageKey) -> bool {
+		match self.storage(from).flatten().map(<[u8]>::to_vec) {
+			Some(value) => {
+				self.set_storage(to, Some(value));
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Exchange the values of the keys `a` and `b`.
+	///
+	/// A key that is unknown to the overlay is treated as deleted, so swapping it with a
+	/// present key deletes the present key. Backend values are not consulted.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn swap_storage(&mut self, a: &[u8], b: &[u8]) {
+		let value_a = self.storage(a).flatten().map(<[u8]>::to_vec);
+		let value_b = self.storage(b).flatten().map(<[u8]>::to_vec);
+		self.set_storage(a.to_vec(), value_b);
+		self.set_storage(b.to_vec(), value_a);
+	}
+
+	/// Remove the value of the specified key.
+	///
+	/// Same as `set_storage(key, None)`, but returns the value the overlay held for the
+	/// key before the removal using the double-Option of [`Self::storage`].
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn remove_storage(&mut self, key: StorageKey) -> Option<Option<StorageValue>> {
+		let prior = self.top.get(&key).map(|v| v.value().cloned());
+		self.set_storage(key, None);
+		prior
+	}
+
+	/// Set a new value for the specified borrowed key.
+	///
+	/// Same as [`Self::set_storage`], but the key is only copied when the overlay does not
+	/// know it yet, which saves an allocation when overwriting.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_storage_ref(&mut self, key: &[u8], val: Option<StorageValue>) {
+		self.record(|| OverlayOp::SetStorage { key: key.to_vec(), value: val.clone() });
+		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+		self.stats.tally_write_overlay(size_write);
+		let val = self.normalize_value(val);
+		self.note_top_changes(Some(key));
+		#[cfg(feature = "std")]
+		self.note_immediate_commits(None, Some(key));
+		self.top.set_ref(key, val, self.extrinsic_index());
+	}
+
+	/// Set a new value for the specified key and attribute it to the given extrinsics.
+	///
+	/// Instead of the current extrinsic index, `extrinsics` are recorded as the extrinsics that
+	/// changed the value in the current transaction. This is meant for importing state that
+	/// already carries extrinsic attribution.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_storage_with_extrinsics(
 		&mut self,
-		child_info: &ChildInfo,
 		key: StorageKey,
 		val: Option<StorageValue>,
+		extrinsics: BTreeSet<ExtrinsicIndex>,
 	) {
-		let extrinsic_index = self.extrinsic_index();
+		self.record(|| OverlayOp::SetStorageWithExtrinsics {
+			key: key.clone(),
+			value: val.clone(),
+			extrinsics: extrinsics.clone(),
+		});
 		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_write_overlay(size_write);
-		let storage_key = child_info.storage_key().to_vec();
-		let top = &self.top;
-		let (changeset, info) = self.children.entry(storage_key).or_insert_with(||
+		let val = self.normalize_value(val);
+		self.note_top_changes(Some(&key));
+		#[cfg(feature = "std")]
+		self.note_immediate_commits(None, Some(&key));
+		self.top.set_with_extrinsics(key, val, extrinsics);
+	}
+
+	/// Replace every overlayed top value whose key starts with `prefix` by the result of `f`.
+	///
+	/// `f` receives the key and the currently visible value and returns the new value, where
+	/// `None` deletes the key. Keys not present in the overlay are not visited. Returns the
+	/// number of transformed keys.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn transform_prefix<F>(&mut self, prefix: &[u8], mut f: F) -> u32
+	where
+		F: FnMut(&[u8], Option<StorageValue>) -> Option<StorageValue>,
+	{
+		let keys: Vec<StorageKey> = self.top.changes_from(prefix)
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.map(|(key, _)| key.to_vec())
+			.collect();
+		let mut count = 0;
+		for key in keys {
+			let value = self.top.get(&key).and_then(|v| v.value().cloned());
+			let value = f(&key, value);
+			self.set_storage(key, value);
+			count += 1;
+		}
+		count
+	}
+
+	/// Replace every overlayed value of the given child whose key starts with `prefix` by the
+	/// result of `f`.
+	///
+	/// Same as [`Self::transform_prefix`], but for a child. Returns `0` if the overlay holds
+	/// no changes for the child.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn child_transform_prefix<F>(&mut self, storage_key: &[u8], prefix: &[u8], mut f: F) -> u32
+	where
+		F: FnMut(&[u8], Option<StorageValue>) -> Option<StorageValue>,
+	{
+		let (changeset, child_info) = match self.children.get(storage_key) {
+			Some(child) => child,
+			None => return 0,
+		};
+		let child_info = child_info.clone();
+		let entries: Vec<_> = changeset.changes_from(prefix)
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.map(|(key, value)| (key.to_vec(), value.value().cloned()))
+			.collect();
+		let mut count = 0;
+		for (key, value) in entries {
+			let value = f(&key, value);
+			self.set_child_storage(&child_info, key, value);
+			count += 1;
+		}
+		count
+	}
+
+	/// Set a new value for the specified key and child.
+	///
+	/// `None` can be used to delete a value specified by the given key.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub(crate) fn set_child_storage(
+		&mut self,
+		child_info: &ChildInfo,
+		key: StorageKey,
+		val: Option<StorageValue>,
+	) {
+		self.record(|| OverlayOp::SetChildStorage {
+			child_info: child_info.clone(),
+			key: key.clone(),
+			value: val.clone(),
+		});
+		#[cfg(feature = "std")]
+		self.note_immediate_commits(Some(child_info.storage_key()), Some(&key));
+		let extrinsic_index = self.extrinsic_index();
+		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+		self.stats.tally_write_overlay(size_write);
+		let val = self.normalize_value(val);
+		let storage_key = child_info.storage_key().to_vec();
+		let top = &self.top;
+		let (changeset, info) = self.children.entry(storage_key).or_insert_with(||
 			(
 				top.spawn_child(),
 				child_info.clone()
@@ -333,9 +1233,152 @@ impl OverlayedChanges {
 		);
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
+		count_live_write(self.child_live_keys.get_mut(child_info.storage_key()), changeset, &key, &val);
 		changeset.set(key, val, extrinsic_index);
 	}
 
+	/// Set a new value for the specified key in the default child trie named by `storage_key`.
+	///
+	/// `storage_key` is the child storage key without the `:child_storage:default:` prefix,
+	/// i.e. the key under which the child is reported by [`Self::child_changes`]. It must
+	/// be valid according to [`validate_child_keyspace`].
+	///
+	/// `None` can be used to delete a value specified by the given key.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_child_storage_value(
+		&mut self,
+		storage_key: StorageKey,
+		key: StorageKey,
+		val: Option<StorageValue>,
+	) -> Result<(), InvalidChildKeyspace> {
+		validate_child_keyspace(&storage_key)?;
+		let child_info = ChildInfo::new_default_from_vec(storage_key);
+		self.set_child_storage(&child_info, key, val);
+		Ok(())
+	}
+
+	/// Set many values in the child with the given storage key at once.
+	///
+	/// Same as calling [`Self::set_child_storage_value`] for every item, but the extrinsic
+	/// index is determined and the child is looked up only once for the whole batch.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_child_storage_many(
+		&mut self,
+		storage_key: StorageKey,
+		items: impl IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+	) -> Result<(), InvalidChildKeyspace> {
+		validate_child_keyspace(&storage_key)?;
+		let items: Vec<_> = items.into_iter().collect();
+		#[cfg(feature = "std")]
+		self.note_immediate_commits(Some(&storage_key), items.iter().map(|(key, _)| key));
+		let extrinsic_index = self.extrinsic_index();
+		let child_info = ChildInfo::new_default_from_vec(storage_key.clone());
+		let top = &self.top;
+		let (changeset, _) = self.children.entry(storage_key).or_insert_with(||
+			(top.spawn_child(), child_info.clone())
+		);
+		for (key, val) in items {
+			if let Some(recording) = &mut self.recording {
+				recording.push(OverlayOp::SetChildStorage {
+					child_info: child_info.clone(),
+					key: key.clone(),
+					value: val.clone(),
+				});
+			}
+			let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+			self.stats.tally_write_overlay(size_write);
+			let val = match val {
+				Some(val) if self.treat_empty_as_deleted && val.is_empty() => None,
+				val => val,
+			};
+			count_live_write(self.child_live_keys.get_mut(child_info.storage_key()), changeset, &key, &val);
+			changeset.set(key, val, extrinsic_index);
+		}
+		Ok(())
+	}
+
+	/// Write a precomputed diff of top and child changes to the overlay.
+	///
+	/// `children` holds the changes per child storage key, which must be valid according to
+	/// [`validate_child_keyspace`]. Nothing is written if any of them is invalid.
+	/// `None` values delete the respective key.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn apply_diff(
+		&mut self,
+		diff: impl IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+		children: impl IntoIterator<Item=(StorageKey, Vec<(StorageKey, Option<StorageValue>)>)>,
+	) -> Result<(), InvalidChildKeyspace> {
+		let children: Vec<_> = children.into_iter().collect();
+		for (storage_key, _) in &children {
+			validate_child_keyspace(storage_key)?;
+		}
+		for (key, value) in diff {
+			self.set_storage(key, value);
+		}
+		for (storage_key, changes) in children {
+			self.set_child_storage_many(storage_key, changes)?;
+		}
+		Ok(())
+	}
+
+	/// Limit the number of live keys the overlay may hold for the given child storage key.
+	///
+	/// Only writes through [`Self::try_set_child_storage`] are checked against the limit.
+	pub fn set_child_max_keys(&mut self, storage_key: StorageKey, limit: usize) {
+		self.child_key_limits.insert(storage_key, limit);
+	}
+
+	/// Like [`Self::set_child_storage`], but fails if the child storage key is not valid
+	/// according to [`validate_child_keyspace`] or if the write would make the child hold
+	/// more live keys than the limit set with [`Self::set_child_max_keys`].
+	///
+	/// Deletions and updates of already live keys are not subject to the key limit.
+	pub fn try_set_child_storage(
+		&mut self,
+		child_info: &ChildInfo,
+		key: StorageKey,
+		val: Option<StorageValue>,
+	) -> Result<(), ChildWriteError> {
+		let storage_key = child_info.storage_key();
+		validate_child_keyspace(storage_key)?;
+		if let (Some(limit), Some(_)) = (self.child_key_limits.get(storage_key), &val) {
+			let live = match self.children.get(storage_key) {
+				Some((changeset, _)) if changeset.get(&key).and_then(|v| v.value()).is_some() => None,
+				Some((changeset, _)) => Some(*self.child_live_keys.entry(storage_key.to_vec())
+					.or_insert_with(|| changeset.changes().filter(|(_, v)| v.value().is_some()).count())),
+				None => Some(0),
+			};
+			if matches!(live, Some(live) if live >= *limit) {
+				return Err(ChildWriteError::KeyLimitExceeded);
+			}
+		}
+		self.set_child_storage(child_info, key, val);
+		Ok(())
+	}
+
+	/// Remove the value of the specified key from the given child.
+	///
+	/// Same as `set_child_storage(child_info, key, None)`, but returns the value the overlay
+	/// held for the key before the removal. The storage key of the child must be valid
+	/// according to [`validate_child_keyspace`].
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn remove_child_storage(
+		&mut self,
+		child_info: &ChildInfo,
+		key: StorageKey,
+	) -> Result<Option<Option<StorageValue>>, InvalidChildKeyspace> {
+		validate_child_keyspace(child_info.storage_key())?;
+		let prior = self.children.get(child_info.storage_key())
+			.and_then(|(changeset, _)| changeset.get(&key))
+			.map(|v| v.value().cloned());
+		self.set_child_storage(child_info, key, None);
+		Ok(prior)
+	}
+
 	/// Clear child storage of given storage key.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
@@ -343,6 +1386,14 @@ impl OverlayedChanges {
 		&mut self,
 		child_info: &ChildInfo,
 	) {
+		self.record(|| OverlayOp::ClearChildStorage { child_info: child_info.clone() });
+		if self.skip_clear_of_missing_child && !self.children.contains_key(child_info.storage_key()) {
+			// Report the clear even though no entry is created for the child.
+			self.mark_child_cleared(child_info.storage_key());
+			return;
+		}
+		#[cfg(feature = "std")]
+		self.note_immediate_clear(Some(child_info.storage_key()), &[]);
 		let extrinsic_index = self.extrinsic_index();
 		let storage_key = child_info.storage_key().to_vec();
 		let top = &self.top;
@@ -355,12 +1406,149 @@ impl OverlayedChanges {
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
 		changeset.clear_where(|_, _| true, extrinsic_index);
+		self.mark_child_cleared(child_info.storage_key());
+	}
+
+	/// Move all values of the child with storage key `from` to the child `to`.
+	///
+	/// Every key holding a value in `from`, as seen by the current transaction, is written
+	/// to `to` and afterwards all overlayed keys of `from` are deleted. Returns `false`
+	/// without changing anything if the overlay holds no changes for `from`, if `to` is
+	/// rejected by [`validate_child_keyspace`] or if the overlay already holds changes for
+	/// another child `to`.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn rename_child_keyspace(&mut self, from: &[u8], to: Vec<u8>) -> bool {
+		if validate_child_keyspace(&to).is_err() {
+			return false;
+		}
+		let (changeset, from_info) = match self.children.get(from) {
+			Some(child) => child,
+			None => return false,
+		};
+		if from == &to[..] {
+			return true;
+		}
+		if self.children.contains_key(&to) {
+			return false;
+		}
+		let from_info = from_info.clone();
+		let values: Vec<_> = changeset.changes()
+			.filter_map(|(key, value)| value.value().map(|value| (key.clone(), value.clone())))
+			.collect();
+		let to_info = ChildInfo::new_default_from_vec(to);
+		for (key, value) in values {
+			self.set_child_storage(&to_info, key, Some(value));
+		}
+		self.clear_child_storage(&from_info);
+		true
+	}
+
+	/// Clear the overlayed child storage of the given storage key.
+	///
+	/// Unlike [`Self::clear_child_storage`] this does not create an entry for a child that has
+	/// no changes in the overlay, but returns an error instead. Only keys known to the overlay
+	/// are cleared. Returns the number of keys that held a value before clearing.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn try_clear_child_storage(&mut self, storage_key: &[u8]) -> Result<u32, UnknownChild> {
+		#[cfg(feature = "std")]
+		self.note_immediate_clear(Some(storage_key), &[]);
+		let extrinsic_index = self.extrinsic_index();
+		let (changeset, child_info) = self.children.get_mut(storage_key).ok_or(UnknownChild)?;
+		if let Some(recording) = &mut self.recording {
+			recording.push(OverlayOp::ClearChildStorage { child_info: child_info.clone() });
+		}
+		let count = changeset.changes().filter(|(_, v)| v.value().is_some()).count() as u32;
+		changeset.clear_where(|_, _| true, extrinsic_index);
+		self.mark_child_cleared(storage_key);
+		Ok(count)
+	}
+
+	/// Remember that the child with the given storage key was cleared as a whole.
+	fn mark_child_cleared(&mut self, storage_key: &[u8]) {
+		if let Some(live) = self.child_live_keys.get_mut(storage_key) {
+			*live = 0;
+		}
+		let depth = self.transaction_depth();
+		match self.child_clear_depths.get_mut(storage_key) {
+			Some(cleared_at) => *cleared_at = (*cleared_at).min(depth),
+			None => {
+				self.child_clear_depths.insert(storage_key.to_vec(), depth);
+			},
+		}
+	}
+
+	/// Update the clear markers of children after the innermost transaction was closed.
+	fn close_child_clears(&mut self, rolled_back: bool) {
+		let depth = self.transaction_depth();
+		if rolled_back {
+			self.child_clear_depths = sp_std::mem::take(&mut self.child_clear_depths).into_iter()
+				.filter(|(_, cleared_at)| *cleared_at <= depth)
+				.collect();
+		} else {
+			for cleared_at in self.child_clear_depths.values_mut() {
+				*cleared_at = (*cleared_at).min(depth);
+			}
+		}
+	}
+
+	/// Get the storage keys of all children that were cleared as a whole by
+	/// `clear_child_storage`, as seen by the current transaction.
+	///
+	/// A child is reported even if values were written to it after clearing it. The
+	/// order is lexicographic.
+	pub fn cleared_children(&self) -> impl Iterator<Item=&[u8]> {
+		self.child_clear_depths.keys().map(|storage_key| storage_key.as_slice())
+	}
+
+	/// Delete every value in the overlayed child storage of the given storage key.
+	///
+	/// Same as [`Self::try_clear_child_storage`] but returns `0` for a child that has no
+	/// changes in the overlay.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn clear_child_all(&mut self, storage_key: &[u8]) -> u32 {
+		self.try_clear_child_storage(storage_key).unwrap_or(0)
+	}
+
+	/// Delete at most `max` of the overlayed values whose keys share the given prefix.
+	///
+	/// Keys are deleted in lexicographic order and keys that are already deleted are
+	/// skipped, so repeated calls make progress. Returns the number of deleted keys and
+	/// whether no value under the prefix is left in the overlay.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn clear_prefix_limited(&mut self, prefix: &[u8], max: usize) -> (u32, bool) {
+		let (keys, all_cleared) = {
+			let mut live = self.top.changes_from(prefix)
+				.take_while(|(key, _)| key.starts_with(prefix))
+				.filter(|(_, value)| value.value().is_some())
+				.map(|(key, _)| key.to_vec());
+			let keys: Vec<_> = live.by_ref().take(max).collect();
+			(keys, live.next().is_none())
+		};
+		let cleared = keys.len() as u32;
+		for key in keys {
+			self.set_storage(key, None);
+		}
+		(cleared, all_cleared)
 	}
 
 	/// Removes all key-value pairs which keys share the given prefix.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
 	pub(crate) fn clear_prefix(&mut self, prefix: &[u8]) {
+		self.record(|| OverlayOp::ClearPrefix { prefix: prefix.to_vec() });
+		if self.tracks_top_changes() {
+			let cleared: Vec<_> = self.top.changes_from(prefix)
+				.take_while(|(key, _)| key.starts_with(prefix))
+				.map(|(key, _)| key.to_vec())
+				.collect();
+			self.note_top_changes(&cleared);
+		}
+		#[cfg(feature = "std")]
+		self.note_immediate_clear(None, prefix);
 		self.top.clear_where(|key, _| key.starts_with(prefix), self.extrinsic_index());
 	}
 
@@ -372,6 +1560,15 @@ impl OverlayedChanges {
 		child_info: &ChildInfo,
 		prefix: &[u8],
 	) {
+		if self.skip_clear_of_missing_child && !self.children.contains_key(child_info.storage_key()) {
+			return;
+		}
+		self.record(|| OverlayOp::ClearChildPrefix {
+			child_info: child_info.clone(),
+			prefix: prefix.to_vec(),
+		});
+		#[cfg(feature = "std")]
+		self.note_immediate_clear(Some(child_info.storage_key()), prefix);
 		let extrinsic_index = self.extrinsic_index();
 		let storage_key = child_info.storage_key().to_vec();
 		let top = &self.top;
@@ -384,6 +1581,7 @@ impl OverlayedChanges {
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
 		changeset.clear_where(|key, _| key.starts_with(prefix), extrinsic_index);
+		self.child_live_keys.remove(child_info.storage_key());
 	}
 
 	/// Returns the current nesting depth of the transaction stack.
@@ -395,6 +1593,27 @@ impl OverlayedChanges {
 		self.top.transaction_depth()
 	}
 
+	/// Check the internal consistency of the overlay.
+	///
+	/// Verifies that the versions held for every top and child key match the open
+	/// transactions, and that all child changesets are at the same transaction depth
+	/// as the top changeset. Meant to surface corruption early in tests and fuzzing.
+	#[cfg(feature = "std")]
+	pub fn debug_check_invariants(&self) -> Result<(), String> {
+		self.top.check_invariants()?;
+		for (storage_key, (changeset, _)) in self.children.iter() {
+			if changeset.transaction_depth() != self.top.transaction_depth() {
+				return Err(format!(
+					"Child {:?} is at transaction depth {} instead of {}",
+					storage_key, changeset.transaction_depth(), self.top.transaction_depth(),
+				));
+			}
+			changeset.check_invariants()
+				.map_err(|e| format!("Child {:?}: {}", storage_key, e))?;
+		}
+		Ok(())
+	}
+
 	/// Start a new nested transaction.
 	///
 	/// This allows to either commit or roll back all changes that where made while this
@@ -403,11 +1622,153 @@ impl OverlayedChanges {
 	///
 	/// Changes made without any open transaction are committed immediately.
 	pub fn start_transaction(&mut self) {
+		self.record(|| OverlayOp::StartTransaction);
 		self.top.start_transaction();
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.start_transaction();
 		}
 		self.offchain.overlay_mut().start_transaction();
+		self.max_transaction_depth = self.max_transaction_depth.max(self.transaction_depth());
+	}
+
+	/// Start a new nested transaction that can only be closed with the returned token.
+	///
+	/// This prevents one user of a shared overlay from closing a transaction that was
+	/// started by another one. See [`Self::commit_scoped`] and [`Self::rollback_scoped`].
+	pub fn start_transaction_scoped(&mut self) -> TxToken {
+		self.start_transaction();
+		let id = self.next_scope_id;
+		self.next_scope_id += 1;
+		self.scoped_transactions.push((self.transaction_depth(), id));
+		TxToken(id)
+	}
+
+	/// Commit the transaction started by [`Self::start_transaction_scoped`] that returned
+	/// `token`.
+	///
+	/// Returns an error without committing anything if that transaction is not the
+	/// innermost open one.
+	pub fn commit_scoped(&mut self, token: TxToken) -> Result<(), TxScopeError> {
+		self.check_scope(token)?;
+		self.commit_transaction().map_err(|_| TxScopeError::NoOpenTransaction)
+	}
+
+	/// Rollback the transaction started by [`Self::start_transaction_scoped`] that returned
+	/// `token`.
+	///
+	/// Returns an error without rolling back anything if that transaction is not the
+	/// innermost open one.
+	pub fn rollback_scoped(&mut self, token: TxToken) -> Result<(), TxScopeError> {
+		self.check_scope(token)?;
+		self.rollback_transaction().map_err(|_| TxScopeError::NoOpenTransaction)
+	}
+
+	/// Check that `token` belongs to the innermost open transaction.
+	fn check_scope(&self, token: TxToken) -> Result<(), TxScopeError> {
+		if self.transaction_depth() == 0 {
+			return Err(TxScopeError::NoOpenTransaction);
+		}
+		match self.scoped_transactions.last() {
+			Some(&(depth, id)) if depth == self.transaction_depth() && id == token.0 => Ok(()),
+			_ => Err(TxScopeError::TokenMismatch),
+		}
+	}
+
+	/// Forget scoped transactions that were closed.
+	fn prune_scoped_transactions(&mut self) {
+		let depth = self.transaction_depth();
+		while let Some(&(scope_depth, _)) = self.scoped_transactions.last() {
+			if scope_depth <= depth {
+				break;
+			}
+			self.scoped_transactions.pop();
+		}
+	}
+
+	/// Returns the deepest transaction nesting reached since this overlay was created or
+	/// since the last call to [`Self::reset_stats`].
+	pub fn max_transaction_depth(&self) -> usize {
+		self.max_transaction_depth
+	}
+
+	/// Reset the collected statistics and the transaction depth high-water mark.
+	///
+	/// The high-water mark restarts at the current transaction depth. The changes themselves
+	/// are not touched, which allows reusing the overlay across blocks.
+	pub fn reset_stats(&mut self) {
+		self.stats = Default::default();
+		self.max_transaction_depth = self.transaction_depth();
+	}
+
+	/// Ask to track the write generation at which each top key was changed.
+	///
+	/// Tracking is disabled by default because it costs a lookup on every write and a copy
+	/// of every changed key. Disabling it forgets the generations of all keys.
+	pub fn set_track_write_generations(&mut self, track: bool) {
+		self.track_write_generations = track;
+		if !track {
+			self.top_generations.clear();
+		}
+	}
+
+	/// Get the current write generation.
+	///
+	/// While tracking is enabled by `set_track_write_generations`, the generation is advanced
+	/// by every operation that changes top level values, including rolling back a transaction
+	/// that wrote top level keys.
+	pub fn write_generation(&self) -> u64 {
+		self.write_generation
+	}
+
+	/// Get the top keys that were changed after the given write generation.
+	///
+	/// Keys are returned in lexicographic order. Only changes made while tracking was
+	/// enabled are reported, and keys drained with the committed changes are forgotten.
+	pub fn keys_changed_since(&self, generation: u64) -> impl Iterator<Item=&[u8]> {
+		self.top_generations.iter()
+			.filter(move |(_, changed_at)| **changed_at > generation)
+			.map(|(key, _)| key.as_slice())
+	}
+
+	/// Whether changed top keys have to be passed to `note_top_changes`.
+	fn tracks_top_changes(&self) -> bool {
+		self.track_write_generations || self.sorted_top.active
+	}
+
+	/// Remember that the values of `keys` changed, for the write generations and the
+	/// sorted top cache if they are maintained.
+	fn note_top_changes<K: AsRef<[u8]>>(&mut self, keys: impl IntoIterator<Item=K>) {
+		if !self.tracks_top_changes() {
+			return;
+		}
+		if self.track_write_generations {
+			self.write_generation += 1;
+		}
+		for key in keys {
+			let key = key.as_ref();
+			if self.track_write_generations {
+				match self.top_generations.get_mut(key) {
+					Some(changed_at) => *changed_at = self.write_generation,
+					None => {
+						self.top_generations.insert(key.to_vec(), self.write_generation);
+					},
+				}
+			}
+			if self.sorted_top.active && !self.sorted_top.dirty.contains(key) {
+				self.sorted_top.dirty.insert(key.to_vec());
+			}
+		}
+	}
+
+	/// Set a callback that is invoked whenever a transaction is rolled back.
+	///
+	/// The callback receives the top keys written by the rolled back transaction in
+	/// lexicographic order; all changes of the transaction, including child and offchain
+	/// changes, are reverted at the time of the call. `exit_runtime` calls it once with the
+	/// keys of all transactions it rolls back. The hook replaces any previously set hook and
+	/// is not carried over to clones of the overlay.
+	pub fn set_rollback_hook(&mut self, hook: RollbackHook) {
+		self.rollback_hook.0 = Some(hook);
 	}
 
 	/// Rollback the last transaction started by `start_transaction`.
@@ -415,7 +1776,14 @@ impl OverlayedChanges {
 	/// Any changes made during that transaction are discarded. Returns an error if
 	/// there is no open transaction that can be rolled back.
 	pub fn rollback_transaction(&mut self) -> Result<(), NoOpenTransaction> {
+		self.flush_value_mut();
+		let reverted: Vec<_> = if self.tracks_reverted_keys() {
+			self.top.transaction_keys().cloned().collect()
+		} else {
+			Vec::new()
+		};
 		self.top.rollback_transaction()?;
+		self.record(|| OverlayOp::RollbackTransaction);
 		retain_map(&mut self.children, |_, (changeset, _)| {
 			changeset.rollback_transaction()
 				.expect("Top and children changesets are started in lockstep; qed");
@@ -423,65 +1791,352 @@ impl OverlayedChanges {
 		});
 		self.offchain.overlay_mut().rollback_transaction()
 			.expect("Top and offchain changesets are started in lockstep; qed");
+		self.prune_scoped_transactions();
+		self.close_child_clears(true);
+		self.child_live_keys.clear();
+		self.top_keys_reverted(reverted);
 		Ok(())
 	}
 
+	/// Whether rolling back needs to know the reverted top keys.
+	fn tracks_reverted_keys(&self) -> bool {
+		self.tracks_top_changes() || !self.key_tags.is_empty() || self.rollback_hook.0.is_some()
+	}
+
+	/// Update the bookkeeping of the top keys reverted by a rollback and call the rollback hook.
+	///
+	/// Must only be called once all changesets are rolled back, so a panicking hook leaves
+	/// them at the same transaction depth.
+	fn top_keys_reverted(&mut self, mut reverted: Vec<StorageKey>) {
+		reverted.sort_unstable();
+		reverted.dedup();
+		self.note_top_changes(&reverted);
+		for key in &reverted {
+			if self.top.get(key).is_none() {
+				self.key_tags.remove(key);
+			}
+		}
+		if let Some(hook) = &mut self.rollback_hook.0 {
+			hook(&reverted);
+		}
+	}
+
+	/// Rollback the last `count` transactions started by `start_transaction`.
+	///
+	/// Rolls back as many transactions as possible. If fewer than `count` transactions could
+	/// be rolled back, the number of transactions that were not rolled back is returned
+	/// as an error.
+	pub fn rollback_transactions(&mut self, count: usize) -> Result<(), usize> {
+		for done in 0..count {
+			if self.rollback_transaction().is_err() {
+				return Err(count - done);
+			}
+		}
+		Ok(())
+	}
+
+	/// Run `f` inside a new transaction.
+	///
+	/// The transaction is committed or rolled back depending on the returned
+	/// [`TransactionOutcome`]. If `f` panics, the transaction is rolled back before the
+	/// panic is resumed. `f` must leave the transaction stack as it found it.
+	pub fn with_transaction<R, F>(&mut self, f: F) -> R
+	where
+		F: FnOnce(&mut Self) -> TransactionOutcome<R>,
+	{
+		const PROOF: &str = "The transaction was started above and `f` keeps the stack balanced; qed";
+
+		self.start_transaction();
+
+		#[cfg(feature = "std")]
+		let outcome = {
+			use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+			match catch_unwind(AssertUnwindSafe(|| f(self))) {
+				Ok(outcome) => outcome,
+				Err(panic) => {
+					self.rollback_transaction().expect(PROOF);
+					resume_unwind(panic)
+				},
+			}
+		};
+		#[cfg(not(feature = "std"))]
+		let outcome = f(self);
+
+		match outcome {
+			TransactionOutcome::Commit(r) => {
+				self.commit_transaction().expect(PROOF);
+				r
+			},
+			TransactionOutcome::Rollback(r) => {
+				self.rollback_transaction().expect(PROOF);
+				r
+			},
+		}
+	}
+
+	/// Run `f` inside a new transaction that is always rolled back.
+	///
+	/// Returns the result of `f` together with the top level writes it made, sorted by key,
+	/// where `None` is a deletion. The overlay is left as it was before. `f` must leave the
+	/// transaction stack as it found it.
+	pub fn dry_run<R, F>(&mut self, f: F) -> (R, StorageCollection)
+	where
+		F: FnOnce(&mut Self) -> R,
+	{
+		self.with_transaction(|overlay| {
+			let result = f(overlay);
+			let mut writes: StorageCollection = overlay.top.transaction_keys()
+				.map(|key| (key.clone(), overlay.top.get(key).and_then(|v| v.value().cloned())))
+				.collect();
+			writes.sort();
+			TransactionOutcome::Rollback((result, writes))
+		})
+	}
+
 	/// Commit the last transaction started by `start_transaction`.
 	///
 	/// Any changes made during that transaction are committed. Returns an error if there
 	/// is no open transaction that can be committed.
 	pub fn commit_transaction(&mut self) -> Result<(), NoOpenTransaction> {
+		self.flush_value_mut();
+		#[cfg(feature = "std")]
+		let committed_keys = self.keys_to_log_on_commit();
 		self.top.commit_transaction()?;
+		self.record(|| OverlayOp::CommitTransaction);
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.commit_transaction()
 				.expect("Top and children changesets are started in lockstep; qed");
 		}
 		self.offchain.overlay_mut().commit_transaction()
 			.expect("Top and offchain changesets are started in lockstep; qed");
+		self.prune_scoped_transactions();
+		self.close_child_clears(false);
+		#[cfg(feature = "std")]
+		if let Some(committed_keys) = committed_keys {
+			self.write_commit_log(committed_keys);
+		}
 		Ok(())
 	}
 
-	/// Call this before transfering control to the runtime.
+	/// Set a writer that receives a record of the changes made durable by every commit of
+	/// the outermost transaction.
 	///
-	/// This protects all existing transactions from being removed by the runtime.
-	/// Calling this while already inside the runtime will return an error.
-	pub fn enter_runtime(&mut self) -> Result<(), AlreadyInRuntime> {
-		self.top.enter_runtime()?;
-		for (_, (changeset, _)) in self.children.iter_mut() {
-			changeset.enter_runtime()
-				.expect("Top and children changesets are entering runtime in lockstep; qed")
-		}
-		self.offchain.overlay_mut().enter_runtime()
-			.expect("Top and offchain changesets are started in lockstep; qed");
-		Ok(())
+	/// Each record is the SCALE encoded `(StorageCollection, ChildStorageCollection)` of the
+	/// committed keys with their new values, prefixed by its encoded length. Writes made
+	/// while no transaction is open are committed right away. They are part of the record of
+	/// the next outermost commit, or of a record of their own written when the committed
+	/// changes are drained. Once writing fails no more records are written until the error
+	/// is taken with [`Self::take_commit_log_error`].
+	///
+	/// The writer has to be `Send` as the overlay is.
+	#[cfg(feature = "std")]
+	pub fn set_commit_log<W: std::io::Write + Send + 'static>(&mut self, writer: W) {
+		self.commit_log.writer = Some(Box::new(writer));
 	}
 
-	/// Call this when control returns from the runtime.
-	///
-	/// This commits all dangling transaction left open by the runtime.
-	/// Calling this while outside the runtime will return an error.
-	pub fn exit_runtime(&mut self) -> Result<(), NotInRuntime> {
-		self.top.exit_runtime()?;
-		for (_, (changeset, _)) in self.children.iter_mut() {
-			changeset.exit_runtime()
-				.expect("Top and children changesets are entering runtime in lockstep; qed");
-		}
-		self.offchain.overlay_mut().exit_runtime()
-			.expect("Top and offchain changesets are started in lockstep; qed");
-		Ok(())
+	/// Take the error that occurred while writing to the commit log, if any.
+	#[cfg(feature = "std")]
+	pub fn take_commit_log_error(&mut self) -> Option<std::io::Error> {
+		self.commit_log.error.take()
 	}
 
-	/// Consume all changes (top + children) and return them.
-	///
+	/// Whether writes are committed right away and have to be remembered for the commit log.
+	#[cfg(feature = "std")]
+	fn logs_immediate_commits(&self) -> bool {
+		self.commit_log.is_active() && self.transaction_depth() == 0
+	}
+
+	/// Remember keys committed right away for the next commit log record.
+	///
+	/// `storage_key` selects the child the keys belong to, or the top level storage when `None`.
+	#[cfg(feature = "std")]
+	fn note_immediate_commits<K: AsRef<[u8]>>(
+		&mut self,
+		storage_key: Option<&[u8]>,
+		keys: impl IntoIterator<Item=K>,
+	) {
+		if !self.logs_immediate_commits() {
+			return;
+		}
+		let unlogged = match storage_key {
+			None => &mut self.commit_log.unlogged_top,
+			Some(storage_key) => self.commit_log.unlogged_children
+				.entry(storage_key.to_vec())
+				.or_default(),
+		};
+		unlogged.extend(keys.into_iter().map(|key| key.as_ref().to_vec()));
+	}
+
+	/// Remember the overlayed keys under `prefix` that a clear commits right away.
+	#[cfg(feature = "std")]
+	fn note_immediate_clear(&mut self, storage_key: Option<&[u8]>, prefix: &[u8]) {
+		if !self.logs_immediate_commits() {
+			return;
+		}
+		let changeset = match storage_key {
+			None => &self.top,
+			Some(storage_key) => match self.children.get(storage_key) {
+				Some((changeset, _)) => changeset,
+				None => return,
+			},
+		};
+		let cleared: Vec<_> = changeset.changes_from(prefix)
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.map(|(key, _)| key.to_vec())
+			.collect();
+		self.note_immediate_commits(storage_key, cleared);
+	}
+
+	/// Write the keys committed right away that are not logged yet to the commit log.
+	#[cfg(feature = "std")]
+	fn flush_commit_log(&mut self) {
+		let log = &self.commit_log;
+		if !log.unlogged_top.is_empty() || !log.unlogged_children.is_empty() {
+			self.write_commit_log(Default::default());
+		}
+	}
+
+	/// Get the top and child keys the next commit will make durable if it has to be logged.
+	#[cfg(feature = "std")]
+	fn keys_to_log_on_commit(&self) -> Option<CommittedKeys> {
+		if !self.commit_log.is_active() || self.transaction_depth() != 1 {
+			return None;
+		}
+		let sorted_keys = |changeset: &OverlayedChangeSet| {
+			let mut keys: Vec<_> = changeset.transaction_keys().cloned().collect();
+			keys.sort_unstable();
+			keys
+		};
+		let mut children: Vec<_> = self.children.iter()
+			.map(|(storage_key, (changeset, _))| (storage_key.clone(), sorted_keys(changeset)))
+			.filter(|(_, keys)| !keys.is_empty())
+			.collect();
+		children.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+		Some((sorted_keys(&self.top), children))
+	}
+
+	/// Write the committed values of the given keys and of the keys committed right away
+	/// to the commit log.
+	#[cfg(feature = "std")]
+	fn write_commit_log(&mut self, (top, children): CommittedKeys) {
+		use sp_std::mem::take;
+		let mut top_keys = take(&mut self.commit_log.unlogged_top);
+		top_keys.extend(top);
+		let mut child_keys = take(&mut self.commit_log.unlogged_children);
+		for (storage_key, keys) in children {
+			child_keys.entry(storage_key).or_default().extend(keys);
+		}
+		let committed = |changeset: &OverlayedChangeSet, keys: BTreeSet<StorageKey>| -> StorageCollection {
+			keys.into_iter()
+				.map(|key| {
+					let value = changeset.get_committed(&key).and_then(|value| value.clone());
+					(key, value)
+				})
+				.collect()
+		};
+		let top = committed(&self.top, top_keys);
+		let children: ChildStorageCollection = child_keys.into_iter()
+			.filter_map(|(storage_key, keys)| {
+				let (changeset, _) = self.children.get(&storage_key)?;
+				Some((storage_key, committed(changeset, keys)))
+			})
+			.collect();
+		let record = (top, children).encode();
+		if let Some(writer) = &mut self.commit_log.writer {
+			if let Err(e) = record.using_encoded(|e| writer.write_all(e)) {
+				self.commit_log.error = Some(e);
+			}
+		}
+	}
+
+	/// Commit the last transaction started by `start_transaction` and return the number of
+	/// top level keys whose committed value changed.
+	///
+	/// Only committing the outermost transaction changes committed values. Writes that leave
+	/// the committed value as it was are not counted.
+	pub fn commit_transaction_counted(&mut self) -> Result<usize, NoOpenTransaction> {
+		let before: Vec<(StorageKey, Option<Option<StorageValue>>)> = self.top.transaction_keys()
+			.map(|key| (key.clone(), self.top.get_committed(key).cloned()))
+			.collect();
+		self.commit_transaction()?;
+		Ok(before.iter().filter(|(key, value)| self.top.get_committed(key) != value.as_ref()).count())
+	}
+
+	/// Commit the last transaction started by `start_transaction` and return the top level
+	/// keys that were written by it.
+	///
+	/// The returned keys are sorted. Those are the keys whose value from that transaction
+	/// became visible to the parent transaction (or was committed if it was the last one).
+	/// Returns an error if there is no open transaction that can be committed.
+	pub fn commit_transaction_with_keys(&mut self) -> Result<Vec<StorageKey>, NoOpenTransaction> {
+		let mut keys: Vec<_> = self.top.transaction_keys().cloned().collect();
+		self.commit_transaction()?;
+		keys.sort();
+		Ok(keys)
+	}
+
+	/// Call this before transfering control to the runtime.
+	///
+	/// This protects all existing transactions from being removed by the runtime.
+	/// Calling this while already inside the runtime will return an error.
+	pub fn enter_runtime(&mut self) -> Result<(), AlreadyInRuntime> {
+		self.flush_value_mut();
+		self.top.enter_runtime()?;
+		self.record(|| OverlayOp::EnterRuntime);
+		for (_, (changeset, _)) in self.children.iter_mut() {
+			changeset.enter_runtime()
+				.expect("Top and children changesets are entering runtime in lockstep; qed")
+		}
+		self.offchain.overlay_mut().enter_runtime()
+			.expect("Top and offchain changesets are started in lockstep; qed");
+		Ok(())
+	}
+
+	/// Call this when control returns from the runtime.
+	///
+	/// This rolls back all dangling transaction left open by the runtime.
+	/// Calling this while outside the runtime will return an error.
+	pub fn exit_runtime(&mut self) -> Result<(), NotInRuntime> {
+		self.flush_value_mut();
+		let reverted: Vec<_> = if self.tracks_reverted_keys() {
+			self.top.runtime_transaction_keys().cloned().collect()
+		} else {
+			Vec::new()
+		};
+		self.top.exit_runtime()?;
+		self.record(|| OverlayOp::ExitRuntime);
+		for (_, (changeset, _)) in self.children.iter_mut() {
+			changeset.exit_runtime()
+				.expect("Top and children changesets are entering runtime in lockstep; qed");
+		}
+		self.offchain.overlay_mut().exit_runtime()
+			.expect("Top and offchain changesets are started in lockstep; qed");
+		self.prune_scoped_transactions();
+		self.close_child_clears(true);
+		self.child_live_keys.clear();
+		self.top_keys_reverted(reverted);
+		Ok(())
+	}
+
+	/// Consume all changes (top + children) and return them.
+	///
 	/// After calling this function no more changes are contained in this changeset.
 	///
 	/// Panics:
 	/// Panics if `transaction_depth() > 0`
 	fn drain_committed(&mut self) -> (
-		impl Iterator<Item=(StorageKey, Option<StorageValue>)>,
-		impl Iterator<Item=(StorageKey, (impl Iterator<Item=(StorageKey, Option<StorageValue>)>, ChildInfo))>,
+		impl Iterator<Item=StorageChange>,
+		impl Iterator<Item=ChildChanges<impl Iterator<Item=StorageChange>>>,
 	) {
 		use sp_std::mem::take;
+		#[cfg(feature = "std")]
+		self.flush_commit_log();
+		self.key_tags.clear();
+		self.backend_reads.clear();
+		self.child_clear_depths.clear();
+		self.top_generations.clear();
+		self.sorted_top = Default::default();
+		self.child_live_keys.clear();
 		(
 			take(&mut self.top).drain_commited(),
 			take(&mut self.children).into_iter()
@@ -493,6 +2148,178 @@ impl OverlayedChanges {
 		)
 	}
 
+	/// Consume this overlay and write all committed changes (top + children) to `backend`.
+	///
+	/// A value of `None` removes the key from the backend.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	pub fn apply_committed_to<B: BackendMut>(mut self, backend: &mut B) {
+		let (top, children) = self.drain_committed();
+		for (key, value) in top {
+			match value {
+				Some(value) => backend.insert(key, value),
+				None => backend.remove(&key),
+			}
+		}
+		for (_, (changes, child_info)) in children {
+			for (key, value) in changes {
+				match value {
+					Some(value) => backend.insert_child(&child_info, key, value),
+					None => backend.remove_child(&child_info, &key),
+				}
+			}
+		}
+	}
+
+	/// Consume this overlay and stream all committed changes (top + children) to `writer`.
+	///
+	/// The stream is the SCALE encoding of the top [`StorageCollection`] followed by the
+	/// [`ChildStorageCollection`], but entries are encoded one at a time instead of being
+	/// collected in memory first. Use [`Self::read_committed_from`] to decode it.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	#[cfg(feature = "std")]
+	pub fn stream_committed_to<W: std::io::Write>(self, writer: &mut W) -> std::io::Result<()> {
+		let write_changeset = |writer: &mut W, changeset: OverlayedChangeSet| -> std::io::Result<()> {
+			codec::Compact(changeset.changes().count() as u32).using_encoded(|e| writer.write_all(e))?;
+			for entry in changeset.drain_commited() {
+				entry.using_encoded(|e| writer.write_all(e))?;
+			}
+			Ok(())
+		};
+
+		write_changeset(writer, self.top)?;
+		codec::Compact(self.children.len() as u32).using_encoded(|e| writer.write_all(e))?;
+		for (storage_key, (changeset, _)) in self.children {
+			storage_key.using_encoded(|e| writer.write_all(e))?;
+			write_changeset(writer, changeset)?;
+		}
+		Ok(())
+	}
+
+	/// Decode committed changes written by [`Self::stream_committed_to`] from `reader`.
+	#[cfg(feature = "std")]
+	pub fn read_committed_from<R: std::io::Read>(
+		reader: R,
+	) -> Result<(StorageCollection, ChildStorageCollection), codec::Error> {
+		let mut input = codec::IoReader(reader);
+		let top = Decode::decode(&mut input)?;
+		let children = Decode::decode(&mut input)?;
+		Ok((top, children))
+	}
+
+	/// Consume this overlay and return all committed changes (top + children) together with
+	/// the extrinsics that changed them.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	pub fn into_committed_with_extrinsics(self) -> (
+		impl Iterator<Item=(StorageKey, OverlayedValue)>,
+		impl Iterator<Item=ChildChanges<impl Iterator<Item=(StorageKey, OverlayedValue)>>>,
+	) {
+		assert!(self.transaction_depth() == 0, "Drain is not allowed with open transactions.");
+		(
+			self.top.into_changes(),
+			self.children.into_iter()
+				.map(|(key, (changeset, info))| (key, (changeset.into_changes(), info))),
+		)
+	}
+
+	/// Take all committed changes and reset this overlay.
+	///
+	/// Returns the committed top and child changes, followed by the offchain changes and the
+	/// transaction index operations. Afterwards the overlay is empty but keeps its
+	/// configuration, such as whether extrinsics are collected, its rollback hook and its
+	/// transaction commit log, so it can be reused for the next block.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	pub fn take_and_reset(&mut self) -> (
+		impl Iterator<Item=StorageChange>,
+		impl Iterator<Item=ChildChanges<impl Iterator<Item=StorageChange>>>,
+		OffchainChangesCollection,
+		Vec<IndexOperation>,
+	) {
+		self.record(|| OverlayOp::TakeAndReset);
+		let (top, children) = self.drain_committed();
+		let offchain = self.offchain.drain().collect();
+		let index_ops = sp_std::mem::take(&mut self.transaction_index_ops);
+		let mut reset = self.empty_with_config();
+		reset.rollback_hook = sp_std::mem::take(&mut self.rollback_hook);
+		reset.recording = sp_std::mem::take(&mut self.recording);
+		#[cfg(feature = "std")]
+		{
+			reset.commit_log = sp_std::mem::take(&mut self.commit_log);
+		}
+		*self = reset;
+		(top, children, offchain, index_ops)
+	}
+
+	/// Take the committed changes of the child with the given storage key.
+	///
+	/// Changes made in transactions that are still open are kept in the overlay and the
+	/// other children are not touched. Returns `None` if the overlay holds no changes for
+	/// the child.
+	pub fn drain_child_committed(
+		&mut self,
+		storage_key: &[u8],
+	) -> Option<impl Iterator<Item=(StorageKey, Option<StorageValue>)>> {
+		self.record(|| OverlayOp::DrainChildCommitted { storage_key: storage_key.to_vec() });
+		#[cfg(feature = "std")]
+		self.flush_commit_log();
+		let (changeset, _) = self.children.get_mut(storage_key)?;
+		let drained = changeset.drain_committed_only();
+		if changeset.is_empty() {
+			self.children.remove(storage_key);
+		}
+		self.child_live_keys.remove(storage_key);
+		if self.child_clear_depths.get(storage_key) == Some(&0) {
+			self.child_clear_depths.remove(storage_key);
+		}
+		Some(drained.into_iter())
+	}
+
+	/// Consume this overlay and return only the committed top changes.
+	///
+	/// Child changes are dropped without being drained.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	pub fn into_committed_top(self) -> impl Iterator<Item=(StorageKey, Option<StorageValue>)> {
+		self.top.drain_commited()
+	}
+
+	/// Consume this overlay and return all committed changes (top + children), leaving out
+	/// deletions of keys that are not in the backend.
+	///
+	/// `backend_contains` is asked for every deleted key with the child it belongs to, or
+	/// `None` for a top level key, and the deletion is only returned if it yields `true`.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	pub fn into_committed_pruned<F: Fn(Option<&ChildInfo>, &[u8]) -> bool>(
+		mut self,
+		backend_contains: F,
+	) -> (
+		impl Iterator<Item=StorageChange>,
+		impl Iterator<Item=ChildChanges<impl Iterator<Item=StorageChange>>>,
+	) {
+		let (top, children) = self.drain_committed();
+		let backend_contains = Arc::new(backend_contains);
+		let top_contains = backend_contains.clone();
+		let children = children.map(move |(storage_key, (changes, info))| {
+			let backend_contains = backend_contains.clone();
+			let child_info = info.clone();
+			let changes = changes.filter(move |(key, value)|
+				value.is_some() || backend_contains(Some(&child_info), key)
+			);
+			(storage_key, (changes, info))
+		});
+		(top.filter(move |(key, value)| value.is_some() || top_contains(None, key)), children)
+	}
+
 	/// Consume all changes (top + children) and return them.
 	///
 	/// After calling this function no more changes are contained in this changeset.
@@ -500,15 +2327,321 @@ impl OverlayedChanges {
 	/// Panics:
 	/// Panics if `transaction_depth() > 0`
 	pub fn offchain_drain_committed(&mut self) -> impl Iterator<Item=((StorageKey, StorageKey), OffchainOverlayedChange)> {
+		self.record(|| OverlayOp::OffchainDrainCommitted);
 		self.offchain.drain()
 	}
 
+	/// Get an iterator over the keys deleted by the overlay, as seen by the current
+	/// transaction.
+	///
+	/// `storage_key` selects the child to inspect, or the top level storage when `None`.
+	/// Nothing is yielded for a child the overlay holds no changes for.
+	pub fn deleted_keys(&self, storage_key: Option<&[u8]>) -> impl Iterator<Item=&[u8]> {
+		let changeset = match storage_key {
+			None => Some(&self.top),
+			Some(storage_key) => self.children.get(storage_key).map(|(changeset, _)| changeset),
+		};
+		changeset.into_iter()
+			.flat_map(|changeset| changeset.changes())
+			.filter(|(_, value)| value.value().is_none())
+			.map(|(key, _)| key.as_slice())
+	}
+
+	/// Call `f` for every overlayed key and its value as seen by the current transaction,
+	/// in lexicographic key order. Aborts as soon as `f` returns false.
+	///
+	/// `storage_key` selects the child to iterate, or the top level storage when `None`.
+	/// Deletions are passed as `None`. Returns `true` if all values were visited.
+	pub fn apply_to_values_while<F: FnMut(&[u8], Option<&[u8]>) -> bool>(
+		&self,
+		storage_key: Option<&[u8]>,
+		mut f: F,
+	) -> bool {
+		let changeset = match storage_key {
+			None => Some(&self.top),
+			Some(storage_key) => self.children.get(storage_key).map(|(changeset, _)| changeset),
+		};
+		changeset.into_iter()
+			.flat_map(|changeset| changeset.changes())
+			.all(|(key, value)| f(key, value.value().map(|v| v.as_slice())))
+	}
+
+	/// Get an iterator over the keys that hold a value in the overlayed child storage of the
+	/// given storage key, as seen by the current transaction.
+	///
+	/// Deleted keys are skipped and nothing is yielded for an unknown child.
+	pub fn child_keys(&self, storage_key: &[u8]) -> impl Iterator<Item=&[u8]> {
+		self.children.get(storage_key).into_iter()
+			.flat_map(|(changeset, _)| changeset.changes())
+			.filter(|(_, value)| value.value().is_some())
+			.map(|(key, _)| key.as_slice())
+	}
+
+	/// Get all top level changes as seen by the current transaction, sorted by key.
+	///
+	/// Deletions are represented as `None`, so the result can be fed to a trie builder.
+	pub fn sorted_top_changes(&self) -> Vec<(&[u8], Option<&[u8]>)> {
+		// The top changes are held in a `BTreeMap`, so they are already sorted.
+		self.top.changes()
+			.map(|(key, value)| (key.as_slice(), value.value().map(|v| v.as_slice())))
+			.collect()
+	}
+
+	/// Same as [`Self::sorted_top_changes`] but keeps the result around between calls.
+	///
+	/// Returns the sorted top changes together with the indices of the entries that are new
+	/// or whose value changed since the previous call. Keys that disappeared from the overlay
+	/// (e.g. by rolling back a transaction) are simply no longer contained.
+	///
+	/// The first call copies all top changes. From then on the overlay remembers the top keys
+	/// written to, and only those are looked up and compared by the next call. The cache is
+	/// dropped together with the committed changes and is not carried over to clones.
+	pub fn sorted_top_changes_incremental(&mut self)
+		-> (&[(StorageKey, Option<StorageValue>)], &[usize])
+	{
+		let cache = &mut self.sorted_top;
+		cache.changed.clear();
+		if !cache.active {
+			cache.active = true;
+			cache.entries = self.top.changes()
+				.map(|(key, value)| (key.clone(), value.value().cloned()))
+				.collect();
+			cache.changed.extend(0..cache.entries.len());
+			return (&cache.entries, &cache.changed);
+		}
+		if cache.dirty.is_empty() {
+			return (&cache.entries, &cache.changed);
+		}
+
+		let mut previous = sp_std::mem::take(&mut cache.entries).into_iter().peekable();
+		let mut entries = Vec::with_capacity(previous.len());
+		for key in sp_std::mem::take(&mut cache.dirty) {
+			while matches!(previous.peek(), Some((previous_key, _)) if previous_key < &key) {
+				entries.extend(previous.next());
+			}
+			let previous_value = match previous.peek() {
+				Some((previous_key, _)) if previous_key == &key => previous.next().map(|(_, v)| v),
+				_ => None,
+			};
+			if let Some(value) = self.top.get(&key).map(|value| value.value()) {
+				match previous_value {
+					Some(previous_value) if previous_value.as_ref() == value => {
+						entries.push((key, previous_value));
+					},
+					_ => {
+						cache.changed.push(entries.len());
+						entries.push((key, value.cloned()));
+					},
+				}
+			}
+		}
+		entries.extend(previous);
+		cache.entries = entries;
+		(&cache.entries, &cache.changed)
+	}
+
+	/// Get an iterator over every top key the overlay holds an entry for.
+	///
+	/// Deleted keys are included, so this lists all keys touched since the overlay was
+	/// created or last drained.
+	pub fn touched_keys(&self) -> impl Iterator<Item=&[u8]> {
+		self.top.changes().map(|(key, _)| key.as_slice())
+	}
+
+	/// Create a new overlay holding only the committed changes of this one.
+	///
+	/// The new overlay has no open transactions and keeps the configuration of this one,
+	/// but starts with fresh statistics and without recording.
+	pub fn clone_committed_only(&self) -> Self {
+		let children = self.children.iter()
+			.map(|(key, (changeset, info))| (key.clone(), (changeset.clone_committed(), info.clone())))
+			.filter(|(_, (changeset, _))| !changeset.is_empty())
+			.collect();
+		Self {
+			top: self.top.clone_committed(),
+			children,
+			offchain: self.offchain.clone_committed(),
+			transaction_index_ops: self.transaction_index_ops.clone(),
+			..self.empty_with_config()
+		}
+	}
+
+	/// Create an empty overlay that is configured like this one.
+	fn empty_with_config(&self) -> Self {
+		Self {
+			collect_extrinsics: self.collect_extrinsics,
+			extrinsic_tracking_suspended: self.extrinsic_tracking_suspended,
+			child_key_limits: self.child_key_limits.clone(),
+			treat_empty_as_deleted: self.treat_empty_as_deleted,
+			strict_extrinsic_decoding: self.strict_extrinsic_decoding,
+			track_write_generations: self.track_write_generations,
+			skip_clear_of_missing_child: self.skip_clear_of_missing_child,
+			..Default::default()
+		}
+	}
+
 	/// Get an iterator over all child changes as seen by the current transaction.
 	pub fn children(&self)
 		-> impl Iterator<Item=(impl Iterator<Item=(&StorageKey, &OverlayedValue)>, &ChildInfo)> {
 		self.children.iter().map(|(_, v)| (v.0.changes(), &v.1))
 	}
 
+	/// Get the child info of the child with the given storage key.
+	///
+	/// Returns `None` if the overlay holds no changes for that child.
+	pub fn child_info(&self, storage_key: &[u8]) -> Option<&ChildInfo> {
+		self.children.get(storage_key).map(|(_, info)| info)
+	}
+
+	/// Estimate the memory used to track the extrinsics that changed each key.
+	///
+	/// Sums up the size of the extrinsic indices attributed to every top and child key as
+	/// seen by the current transaction. This is zero when extrinsics are not collected.
+	pub fn extrinsic_tracking_bytes(&self) -> usize {
+		sp_std::iter::once(&self.top)
+			.chain(self.children.values().map(|(changeset, _)| changeset))
+			.flat_map(|changeset| changeset.changes())
+			.map(|(_, value)| value.extrinsics().len() * sp_std::mem::size_of::<ExtrinsicIndex>())
+			.sum()
+	}
+
+	/// Returns the highest extrinsic index recorded for any top or child value.
+	///
+	/// Writes outside of extrinsics, recorded as [`NO_EXTRINSIC_INDEX`], are ignored. This is a
+	/// lower bound for the number of extrinsics that changed the storage. `None` is returned
+	/// if extrinsics are not collected or no extrinsic changed a value.
+	pub fn highest_extrinsic_index(&self) -> Option<ExtrinsicIndex> {
+		if !self.collect_extrinsics {
+			return None;
+		}
+		sp_std::iter::once(&self.top)
+			.chain(self.children.values().map(|(changeset, _)| changeset))
+			.flat_map(|changeset| changeset.changes())
+			.filter_map(|(_, value)| {
+				value.extrinsics().into_iter().rev().find(|index| *index != NO_EXTRINSIC_INDEX)
+			})
+			.max()
+	}
+
+	/// Count the top and child values in the overlay by whether they are set or deleted,
+	/// as seen by the current transaction.
+	pub fn changes_summary(&self) -> ChangesSummary {
+		let mut summary = ChangesSummary::default();
+		for (_, value) in self.top.changes() {
+			match value.value() {
+				Some(_) => summary.sets += 1,
+				None => summary.deletes += 1,
+			}
+		}
+		for (_, value) in self.children.values().flat_map(|(changeset, _)| changeset.changes()) {
+			match value.value() {
+				Some(_) => summary.child_sets += 1,
+				None => summary.child_deletes += 1,
+			}
+		}
+		summary
+	}
+
+	/// Get the values of the top storage or of the given child as seen by the current
+	/// transaction, ordered by key.
+	///
+	/// Deletions are represented as `None`. Nothing is returned for an unknown child.
+	pub fn iter_values_sorted(&self, storage_key: Option<&[u8]>)
+		-> impl Iterator<Item=(&[u8], Option<&[u8]>)> {
+		let changeset = match storage_key {
+			Some(storage_key) => self.children.get(storage_key).map(|(changeset, _)| changeset),
+			None => Some(&self.top),
+		};
+		changeset.into_iter()
+			.flat_map(|changeset| changeset.changes())
+			.map(|(k, v)| (k.as_slice(), v.value().map(|v| v.as_slice())))
+	}
+
+	/// Get all child changes as seen by the current transaction, ordered by child storage key.
+	///
+	/// The changes of each child are ordered by key and deletions are represented as `None`.
+	pub fn children_iter_sorted(&self) -> impl Iterator<Item=(
+		&[u8],
+		impl Iterator<Item=StorageChangeRef<'_>>,
+	)> {
+		let mut children: Vec<_> = self.children.iter().collect();
+		children.sort_unstable_by_key(|(storage_key, _)| *storage_key);
+		children.into_iter().map(|(storage_key, (changeset, _))| (
+			storage_key.as_slice(),
+			changeset.changes().map(|(k, v)| (k.as_slice(), v.value().map(|v| v.as_slice()))),
+		))
+	}
+
+	/// Drop children without changes and release unused memory of the child map.
+	///
+	/// Overlayed values themselves are kept in ordered maps that do not hold spare
+	/// capacity, so they are not affected.
+	pub fn shrink_to_fit(&mut self) {
+		retain_map(&mut self.children, |_, (changeset, _)| !changeset.is_empty());
+		#[cfg(feature = "std")]
+		{
+			self.children.shrink_to_fit();
+			self.child_key_limits.shrink_to_fit();
+		}
+	}
+
+	/// Returns the number of children the overlay holds changes for.
+	pub fn child_trie_count(&self) -> usize {
+		self.children.len()
+	}
+
+	/// Returns the number of children that hold at least one value in the overlay.
+	pub fn non_empty_child_trie_count(&self) -> usize {
+		self.children.values()
+			.filter(|(changeset, _)| changeset.changes().any(|(_, v)| v.value().is_some()))
+			.count()
+	}
+
+	/// Get the storage keys of all children whose overlayed values are all deleted.
+	///
+	/// Children without any changes are not included.
+	pub fn emptied_child_keyspaces(&self) -> impl Iterator<Item=&[u8]> {
+		self.children.iter()
+			.filter(|(_, (changeset, _))| {
+				!changeset.is_empty() && changeset.changes().all(|(_, v)| v.value().is_none())
+			})
+			.map(|(storage_key, _)| storage_key.as_slice())
+	}
+
+	/// Get the changes to apply to each child trie in order to recompute its root.
+	///
+	/// Yields the storage key of every child that has changes in the overlay together with
+	/// its key-value pairs as seen by the current transaction, where `None` is a deletion.
+	/// Children without changes are skipped.
+	pub fn child_root_inputs(&self) -> impl Iterator<Item=(
+		&[u8],
+		impl Iterator<Item=StorageChangeRef<'_>>,
+	)> {
+		self.children.iter()
+			.filter(|(_, (changeset, _))| !changeset.is_empty())
+			.map(|(storage_key, (changeset, _))| (
+				storage_key.as_slice(),
+				changeset.changes().map(|(k, v)| (k.as_slice(), v.value().map(|v| v.as_slice()))),
+			))
+	}
+
+	/// Get an iterator over all child changes as seen by the current transaction.
+	///
+	/// Keys and values are borrowed from the overlay. They are wrapped into a [`Cow`] so
+	/// that callers which need to keep some of them around can take ownership selectively.
+	pub fn children_iter_cow(&self) -> impl Iterator<Item=(
+		Cow<'_, [u8]>,
+		impl Iterator<Item=StorageChangeCow<'_>>,
+	)> {
+		self.children.iter().map(|(storage_key, (changeset, _))| (
+			Cow::Borrowed(storage_key.as_slice()),
+			changeset.changes().map(|(k, v)| (
+				Cow::Borrowed(k.as_slice()),
+				v.value().map(|v| Cow::Borrowed(v.as_slice())),
+			)),
+		))
+	}
+
 	/// Get an iterator over all top changes as been by the current transaction.
 	pub fn changes(&self) -> impl Iterator<Item=(&StorageKey, &OverlayedValue)> {
 		self.top.changes()
@@ -520,6 +2653,126 @@ impl OverlayedChanges {
 		self.children.get(key).map(|(overlay, info)| (overlay.changes(), info))
 	}
 
+	/// Collect the extrinsics that changed each key as seen by the current transaction.
+	///
+	/// Only keys with at least one recorded extrinsic are included, which means that
+	/// the result is empty unless extrinsics are collected. Children are ordered by
+	/// their storage key.
+	pub fn changes_trie_input(&self) -> ChangesTrieInput {
+		fn with_extrinsics<'a>(
+			changes: impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)>,
+		) -> ExtrinsicsCollection {
+			changes
+				.map(|(k, v)| (k.clone(), v.extrinsics()))
+				.filter(|(_, extrinsics)| !extrinsics.is_empty())
+				.collect()
+		}
+
+		let mut children: Vec<_> = self.children.iter()
+			.map(|(storage_key, (changeset, _))| (storage_key.clone(), with_extrinsics(changeset.changes())))
+			.filter(|(_, changes)| !changes.is_empty())
+			.collect();
+		children.sort_by(|a, b| a.0.cmp(&b.0));
+
+		ChangesTrieInput {
+			top: with_extrinsics(self.changes()),
+			children,
+		}
+	}
+
+	/// Returns whether any top key starting with `prefix` holds a value in the overlay, as
+	/// seen by the current transaction.
+	///
+	/// Stops at the first key found. Deleted keys do not count.
+	pub fn prefix_exists(&self, prefix: &[u8]) -> bool {
+		self.top.changes_from(prefix)
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.any(|(_, value)| value.value().is_some())
+	}
+
+	/// Collect the extrinsics that changed any top key starting with `prefix`, as seen by
+	/// the current transaction.
+	///
+	/// Deletions count as changes. Returns an empty set when extrinsics are not collected.
+	pub fn extrinsics_union_for_prefix(&self, prefix: &[u8]) -> BTreeSet<ExtrinsicIndex> {
+		if !self.collect_extrinsics {
+			return BTreeSet::new();
+		}
+		self.top.changes_from(prefix)
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.flat_map(|(_, value)| value.extrinsics())
+			.collect()
+	}
+
+	/// Count the (key, extrinsic) pairs a changes trie built from this overlay would contain.
+	///
+	/// Sums the number of extrinsics that changed each top and child key as seen by the
+	/// current transaction. Returns 0 when extrinsics are not collected.
+	pub fn changes_trie_entry_count(&self) -> usize {
+		if !self.collect_extrinsics {
+			return 0;
+		}
+		let count = |changeset: &OverlayedChangeSet| changeset.changes()
+			.map(|(_, value)| value.extrinsics().len())
+			.sum::<usize>();
+		count(&self.top) + self.children.values().map(|(changeset, _)| count(changeset)).sum::<usize>()
+	}
+
+	/// Group the top level keys changed by the current transaction by the extrinsic that
+	/// changed them last.
+	///
+	/// Keys are yielded in ascending extrinsic order and lexicographic order within each
+	/// group. Keys changed outside of extrinsics are grouped under [`NO_EXTRINSIC_INDEX`].
+	/// Nothing is yielded unless extrinsics are collected.
+	pub fn iter_by_last_extrinsic(&self) -> impl Iterator<Item=(ExtrinsicIndex, Vec<&[u8]>)> {
+		let mut groups = BTreeMap::<ExtrinsicIndex, Vec<&[u8]>>::new();
+		if self.collect_extrinsics {
+			for (key, value) in self.changes() {
+				if let Some(last) = value.extrinsics().into_iter().next_back() {
+					groups.entry(last).or_default().push(key.as_slice());
+				}
+			}
+		}
+		groups.into_iter()
+	}
+
+	/// Render all committed changes (top + children) as JSON for diagnostic purposes.
+	///
+	/// The output has the shape `{"top":{KEY:VALUE},"children":{STORAGE_KEY:{KEY:VALUE}}}`
+	/// where all byte strings are `0x` prefixed hex and deleted values are `null`. Keys
+	/// are sorted so that the output is reproducible. Changes of open transactions are
+	/// not included.
+	#[cfg(feature = "std")]
+	pub fn committed_to_json(&self) -> String {
+		use sp_core::hexdisplay::HexDisplay;
+
+		fn render<'a>(changes: impl Iterator<Item=(&'a StorageKey, &'a Option<StorageValue>)>) -> String {
+			let entries: Vec<_> = changes.map(|(key, value)| match value {
+				Some(value) => format!("\"0x{}\":\"0x{}\"", HexDisplay::from(key), HexDisplay::from(value)),
+				None => format!("\"0x{}\":null", HexDisplay::from(key)),
+			}).collect();
+			format!("{{{}}}", entries.join(","))
+		}
+
+		let mut children: Vec<_> = self.children.iter()
+			.filter(|(_, (changeset, _))| changeset.committed_changes().next().is_some())
+			.collect();
+		children.sort_by(|a, b| a.0.cmp(b.0));
+		let children: Vec<_> = children.into_iter()
+			.map(|(storage_key, (changeset, _))| format!(
+				"\"0x{}\":{}",
+				HexDisplay::from(storage_key),
+				render(changeset.committed_changes()),
+			))
+			.collect();
+
+		format!(
+			"{{\"top\":{},\"children\":{{{}}}}}",
+			render(self.top.committed_changes()),
+			children.join(","),
+		)
+	}
+
 	/// Get an list of all index operations.
 	pub fn transaction_index_ops(&self) -> &[IndexOperation] {
 		&self.transaction_index_ops
@@ -576,8 +2829,9 @@ impl OverlayedChanges {
 			.take()
 			.expect("Changes trie transaction was generated by `changes_trie_root`; qed");
 
+		self.record(|| OverlayOp::DrainCommitted);
 		let (main_storage_changes, child_storage_changes) = self.drain_committed();
-		let offchain_storage_changes = self.offchain_drain_committed().collect();
+		let offchain_storage_changes = self.offchain.drain().collect();
 
 		#[cfg(feature = "std")]
 		let transaction_index_changes = std::mem::take(&mut self.transaction_index_ops);
@@ -597,29 +2851,138 @@ impl OverlayedChanges {
 		})
 	}
 
-	/// Inserts storage entry responsible for current extrinsic index.
-	#[cfg(test)]
-	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: u32) {
-		self.top.set(EXTRINSIC_INDEX.to_vec(), Some(extrinsic_index.encode()), None);
+	/// Start recording all mutating operations applied to this overlay.
+	///
+	/// Operations already recorded are kept. A value changed in place through
+	/// [`Self::value_mut_or_insert_with`] is recorded with its final value once the next
+	/// operation is applied or the recording is taken.
+	pub fn start_recording(&mut self) {
+		self.recording.get_or_insert_with(Vec::new);
 	}
 
-	/// Returns current extrinsic index to use in changes trie construction.
-	/// None is returned if it is not set or changes trie config is not set.
-	/// Persistent value (from the backend) can be ignored because runtime must
-	/// set this index before first and unset after last extrinsic is executed.
-	/// Changes that are made outside of extrinsics, are marked with
-	/// `NO_EXTRINSIC_INDEX` index.
-	fn extrinsic_index(&self) -> Option<u32> {
-		match self.collect_extrinsics {
-			true => Some(
-				self.storage(EXTRINSIC_INDEX)
-					.and_then(|idx| idx.and_then(|idx| Decode::decode(&mut &*idx).ok()))
-					.unwrap_or(NO_EXTRINSIC_INDEX)),
-			false => None,
-		}
+	/// Take the operations recorded so far.
+	///
+	/// Recording continues if it was started.
+	pub fn take_recording(&mut self) -> Vec<OverlayOp> {
+		self.flush_value_mut();
+		self.recording.as_mut().map(sp_std::mem::take).unwrap_or_default()
 	}
 
-	/// Generate the storage root using `backend` and all changes
+	/// Build an overlay by applying `ops` to an empty overlay.
+	///
+	/// The result is equivalent to the recorded overlay if the recording was started on
+	/// an empty overlay. Transaction operations that fail are ignored.
+	pub fn replay(ops: &[OverlayOp]) -> Self {
+		let mut overlay = Self::default();
+		for op in ops {
+			match op.clone() {
+				OverlayOp::SetCollectExtrinsics(collect) => overlay.set_collect_extrinsics(collect),
+				OverlayOp::SetTreatEmptyAsDeleted(treat) => overlay.set_treat_empty_as_deleted(treat),
+				OverlayOp::SetStorage { key, value } => overlay.set_storage(key, value),
+				OverlayOp::SetStorageWithExtrinsics { key, value, extrinsics } =>
+					overlay.set_storage_with_extrinsics(key, value, extrinsics),
+				OverlayOp::SetChildStorage { child_info, key, value } =>
+					overlay.set_child_storage(&child_info, key, value),
+				OverlayOp::ClearChildStorage { child_info } =>
+					overlay.clear_child_storage(&child_info),
+				OverlayOp::ClearPrefix { prefix } => overlay.clear_prefix(&prefix),
+				OverlayOp::ClearChildPrefix { child_info, prefix } =>
+					overlay.clear_child_prefix(&child_info, &prefix),
+				OverlayOp::StartTransaction => overlay.start_transaction(),
+				OverlayOp::CommitTransaction => { let _ = overlay.commit_transaction(); },
+				OverlayOp::RollbackTransaction => { let _ = overlay.rollback_transaction(); },
+				OverlayOp::EnterRuntime => { let _ = overlay.enter_runtime(); },
+				OverlayOp::ExitRuntime => { let _ = overlay.exit_runtime(); },
+				OverlayOp::DrainProspective => { overlay.drain_prospective(); },
+				OverlayOp::ModifyStorage { key, value } =>
+					*overlay.value_mut_or_insert_with(&key, StorageValue::default) = value,
+				OverlayOp::SetStrictExtrinsicDecoding(strict) =>
+					overlay.set_strict_extrinsic_decoding(strict),
+				OverlayOp::SetCreateMissingChildOnClear(create) =>
+					overlay.set_create_missing_child_on_clear(create),
+				OverlayOp::SuspendExtrinsicTracking => overlay.suspend_extrinsic_tracking(),
+				OverlayOp::ResumeExtrinsicTracking => overlay.resume_extrinsic_tracking(),
+				OverlayOp::DrainChildCommitted { storage_key } =>
+					{ let _ = overlay.drain_child_committed(&storage_key); },
+				OverlayOp::DrainCommitted => {
+					let _ = overlay.drain_committed();
+					let _ = overlay.offchain.drain();
+					#[cfg(feature = "std")]
+					overlay.transaction_index_ops.clear();
+				},
+				OverlayOp::OffchainDrainCommitted => { let _ = overlay.offchain_drain_committed(); },
+				OverlayOp::TakeAndReset => { let _ = overlay.take_and_reset(); },
+				OverlayOp::SetOffchainStorage { key, value } =>
+					overlay.set_offchain_storage(&key, value.as_deref()),
+				OverlayOp::AddTransactionIndex(op) => overlay.add_transaction_index(op),
+			}
+		}
+		overlay
+	}
+
+	/// Record the operation built by `op` if recording.
+	fn record(&mut self, op: impl FnOnce() -> OverlayOp) {
+		self.flush_value_mut();
+		if let Some(recording) = &mut self.recording {
+			recording.push(op());
+		}
+	}
+
+	/// Record the final value of the key handed out by `value_mut_or_insert_with`, if any.
+	fn flush_value_mut(&mut self) {
+		let key = match self.pending_value_mut.take() {
+			Some(key) => key,
+			None => return,
+		};
+		let value = self.top.get(&key).and_then(|value| value.value()).cloned().unwrap_or_default();
+		if let Some(recording) = &mut self.recording {
+			recording.push(OverlayOp::ModifyStorage { key, value });
+		}
+	}
+
+	/// Inserts storage entry responsible for current extrinsic index.
+	#[cfg(test)]
+	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: ExtrinsicIndex) {
+		self.top.set(EXTRINSIC_INDEX.to_vec(), Some(extrinsic_index.encode()), None);
+	}
+
+	/// Returns current extrinsic index to use in changes trie construction.
+	/// None is returned if it is not set or changes trie config is not set.
+	/// Persistent value (from the backend) can be ignored because runtime must
+	/// set this index before first and unset after last extrinsic is executed.
+	/// Changes that are made outside of extrinsics, are marked with
+	/// `NO_EXTRINSIC_INDEX` index. None is also returned while extrinsic tracking
+	/// is suspended.
+	///
+	/// A malformed index is treated as `NO_EXTRINSIC_INDEX`. In strict mode the decoding
+	/// error is recorded for `take_extrinsic_index_error`.
+	fn extrinsic_index(&self) -> Option<ExtrinsicIndex> {
+		self.checked_extrinsic_index().unwrap_or_else(|e| {
+			self.extrinsic_index_error.borrow_mut().get_or_insert(e);
+			Some(NO_EXTRINSIC_INDEX)
+		})
+	}
+
+	/// Get the current extrinsic index like `extrinsic_index`, but report a value stored
+	/// under `EXTRINSIC_INDEX` that can not be decoded.
+	///
+	/// A malformed value is only reported when strict extrinsic decoding is enabled,
+	/// otherwise it is treated as `NO_EXTRINSIC_INDEX`.
+	pub fn checked_extrinsic_index(&self) -> Result<Option<ExtrinsicIndex>, codec::Error> {
+		if !self.collect_extrinsics || self.extrinsic_tracking_suspended {
+			return Ok(None);
+		}
+		match self.storage(EXTRINSIC_INDEX) {
+			Some(Some(mut idx)) => match Decode::decode(&mut idx) {
+				Ok(idx) => Ok(Some(idx)),
+				Err(e) if self.strict_extrinsic_decoding => Err(e),
+				Err(_) => Ok(Some(NO_EXTRINSIC_INDEX)),
+			},
+			_ => Ok(Some(NO_EXTRINSIC_INDEX)),
+		}
+	}
+
+	/// Generate the storage root using `backend` and all changes
 	/// as seen by the current transaction.
 	///
 	/// Returns the storage root and caches storage transaction in the given `cache`.
@@ -674,6 +3037,26 @@ impl OverlayedChanges {
 		})
 	}
 
+	/// Returns an iterator over all top key-value pairs starting with `prefix` as seen through
+	/// the overlay, in lexicographic order of the keys.
+	///
+	/// Overlayed values shadow the values of `backend` and overlayed deletions hide them.
+	pub fn merged_iter<'a, B: BackendIter>(
+		&'a self,
+		backend: &'a B,
+		prefix: &[u8],
+	) -> MergedStorageIter<'a, B> {
+		let owned_prefix = prefix.to_vec();
+		let overlay = self.top.changes_from(prefix)
+			.take_while(move |(key, _)| key.starts_with(&owned_prefix))
+			.map(|(key, value)| (key, value.value().map(|v| v.as_slice())));
+		MergedStorageIter {
+			overlay: (Box::new(overlay) as BoxedIter<_>).peekable(),
+			backend: backend.iter_prefix(prefix).peekable(),
+			_marker: Default::default(),
+		}
+	}
+
 	/// Returns an iterator over the keys (in lexicographic order) following `key` (excluding `key`)
 	/// alongside its value.
 	pub fn iter_after(&self, key: &[u8]) -> impl Iterator<Item = (&[u8], &OverlayedValue)> {
@@ -702,6 +3085,10 @@ impl OverlayedChanges {
 	/// Write a key value pair to the offchain storage overlay.
 	pub fn set_offchain_storage(&mut self, key: &[u8], value: Option<&[u8]>) {
 		use sp_core::offchain::STORAGE_PREFIX;
+		self.record(|| OverlayOp::SetOffchainStorage {
+			key: key.to_vec(),
+			value: value.map(|value| value.to_vec()),
+		});
 		match value {
 			Some(value) => self.offchain.set(STORAGE_PREFIX, key, value),
 			None => self.offchain.remove(STORAGE_PREFIX, key),
@@ -710,6 +3097,7 @@ impl OverlayedChanges {
 
 	/// Add transaction index operation.
 	pub fn add_transaction_index(&mut self, op: IndexOperation) {
+		self.record(|| OverlayOp::AddTransactionIndex(op.clone()));
 		self.transaction_index_ops.push(op)
 	}
 }
@@ -723,6 +3111,70 @@ fn retain_map<K, V, F>(map: &mut Map<K, V>, f: F)
 	map.retain(f);
 }
 
+/// Update the live key count of a child, if it is counted, for writing `val` to `key`.
+fn count_live_write(
+	live: Option<&mut usize>,
+	changeset: &OverlayedChangeSet,
+	key: &[u8],
+	val: &Option<StorageValue>,
+) {
+	if let Some(live) = live {
+		match (changeset.get(key).and_then(|v| v.value()).is_some(), val.is_some()) {
+			(false, true) => *live += 1,
+			(true, false) => *live -= 1,
+			_ => (),
+		}
+	}
+}
+
+/// Check that `storage_key` can name a child trie.
+///
+/// `storage_key` is the child storage key without the `:child_storage:default:` prefix, as
+/// returned by [`ChildInfo::storage_key`]. It must be non-empty and must not itself start
+/// with the child storage prefix.
+pub fn validate_child_keyspace(storage_key: &[u8]) -> Result<(), InvalidChildKeyspace> {
+	if storage_key.is_empty() || well_known_keys::is_child_storage_key(storage_key) {
+		Err(InvalidChildKeyspace)
+	} else {
+		Ok(())
+	}
+}
+
+type BoxedIter<'a, T> = Box<dyn Iterator<Item = T> + 'a>;
+
+/// Iterator over the top storage as seen through the overlay, see
+/// [`OverlayedChanges::merged_iter`].
+pub struct MergedStorageIter<'a, B: BackendIter> {
+	overlay: Peekable<BoxedIter<'a, StorageChangeRef<'a>>>,
+	backend: Peekable<BoxedIter<'a, (StorageKey, StorageValue)>>,
+	_marker: PhantomData<&'a B>,
+}
+
+impl<'a, B: BackendIter> Iterator for MergedStorageIter<'a, B> {
+	type Item = (StorageKey, StorageValue);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let order = match (self.overlay.peek(), self.backend.peek()) {
+				(None, None) => return None,
+				(Some(_), None) => Ordering::Less,
+				(None, Some(_)) => Ordering::Greater,
+				(Some((overlay_key, _)), Some((backend_key, _))) =>
+					(*overlay_key).cmp(backend_key.as_slice()),
+			};
+			match order {
+				Ordering::Greater => return self.backend.next(),
+				// The overlay shadows the backend.
+				Ordering::Equal => { self.backend.next(); },
+				Ordering::Less => (),
+			}
+			if let Some((key, Some(value))) = self.overlay.next() {
+				return Some((key.to_vec(), value.to_vec()));
+			}
+		}
+	}
+}
+
 #[cfg(not(feature = "std"))]
 fn retain_map<K, V, F>(map: &mut Map<K, V>, mut f: F)
 	where
@@ -981,6 +3433,2413 @@ mod tests {
 		assert_extrinsics(&overlay.top, vec![100], vec![NO_EXTRINSIC_INDEX]);
 	}
 
+	#[test]
+	fn changes_trie_input_is_collected() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.start_transaction();
+
+		overlay.set_storage(vec![100], Some(vec![101]));
+
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![2]));
+
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![3], Some(vec![4]));
+		overlay.set_child_storage(&child_info, vec![5], Some(vec![6]));
+
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], Some(vec![6]));
+
+		let input = overlay.changes_trie_input();
+		assert_eq!(input.top, vec![
+			(vec![1], vec![0, 2].into_iter().collect()),
+			(vec![3], vec![1].into_iter().collect()),
+			(vec![100], vec![NO_EXTRINSIC_INDEX].into_iter().collect()),
+		]);
+		assert_eq!(input.children, vec![
+			(b"Child1".to_vec(), vec![(vec![5], vec![1].into_iter().collect())]),
+		]);
+
+		overlay.set_collect_extrinsics(false);
+		overlay.set_storage(vec![7], Some(vec![8]));
+		assert!(overlay.changes_trie_input().top.iter().all(|(k, _)| k != &vec![7]));
+	}
+
+	#[test]
+	fn children_iter_cow_borrows_from_overlay() {
+		let child_info1 = ChildInfo::new_default(b"Child1");
+		let child_info2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child_info1, vec![1], Some(vec![11]));
+		overlay.set_child_storage(&child_info1, vec![2], None);
+		overlay.set_child_storage(&child_info2, vec![3], Some(vec![33]));
+
+		let mut owned: ChildStorageCollection = overlay.children
+			.iter()
+			.map(|(storage_key, (changeset, _))| (
+				storage_key.clone(),
+				changeset.changes().map(|(k, v)| (k.clone(), v.value().cloned())).collect(),
+			))
+			.collect();
+		owned.sort();
+
+		let mut borrowed = Vec::new();
+		for (storage_key, changes) in overlay.children_iter_cow() {
+			assert!(matches!(storage_key, Cow::Borrowed(_)));
+			let changeset = &overlay.children.get(storage_key.as_ref()).unwrap().0;
+			let changes: Vec<_> = changes.map(|(k, v)| {
+				let entry = changeset.get(k.as_ref()).unwrap();
+				match (&k, &v) {
+					(Cow::Borrowed(k), Some(Cow::Borrowed(v))) => {
+						assert_eq!(v.as_ptr(), entry.value().unwrap().as_ptr());
+						assert!(changeset.changes().any(|(key, _)| key.as_ptr() == k.as_ptr()));
+					},
+					(Cow::Borrowed(k), None) => {
+						assert!(changeset.changes().any(|(key, _)| key.as_ptr() == k.as_ptr()));
+					},
+					_ => panic!("Values must be borrowed"),
+				}
+				(k.into_owned(), v.map(Cow::into_owned))
+			}).collect();
+			borrowed.push((storage_key.into_owned(), changes));
+		}
+		borrowed.sort();
+
+		assert_eq!(borrowed, owned);
+	}
+
+	#[test]
+	fn apply_committed_to_writes_committed_changes() {
+		#[derive(Default)]
+		struct MemoryBackend {
+			top: BTreeMap<StorageKey, StorageValue>,
+			children: BTreeMap<StorageKey, BTreeMap<StorageKey, StorageValue>>,
+		}
+
+		impl BackendMut for MemoryBackend {
+			fn insert(&mut self, key: StorageKey, value: StorageValue) {
+				self.top.insert(key, value);
+			}
+
+			fn remove(&mut self, key: &[u8]) {
+				self.top.remove(key);
+			}
+
+			fn insert_child(&mut self, child_info: &ChildInfo, key: StorageKey, value: StorageValue) {
+				self.children.entry(child_info.storage_key().to_vec()).or_default().insert(key, value);
+			}
+
+			fn remove_child(&mut self, child_info: &ChildInfo, key: &[u8]) {
+				if let Some(child) = self.children.get_mut(child_info.storage_key()) {
+					child.remove(key);
+				}
+			}
+		}
+
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut backend = MemoryBackend::default();
+		backend.insert(vec![1], vec![1]);
+		backend.insert(vec![2], vec![2]);
+		backend.insert_child(&child_info, vec![3], vec![3]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], None);
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.set_child_storage(&child_info, vec![3], None);
+		overlay.set_child_storage(&child_info, vec![5], Some(vec![5]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], None);
+		overlay.rollback_transaction().unwrap();
+
+		overlay.apply_committed_to(&mut backend);
+
+		assert_eq!(
+			backend.top.into_iter().collect::<Vec<_>>(),
+			vec![(vec![2], vec![2]), (vec![4], vec![4])],
+		);
+		assert_eq!(
+			backend.children.into_iter().collect::<Vec<_>>(),
+			vec![(b"Child1".to_vec(), vec![(vec![5], vec![5])].into_iter().collect())],
+		);
+	}
+
+	#[test]
+	fn keys_are_grouped_by_last_extrinsic() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![9], Some(vec![9]));
+		assert_eq!(overlay.iter_by_last_extrinsic().count(), 0);
+
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(vec![100], Some(vec![100]));
+
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], None);
+		overlay.set_storage(vec![4], Some(vec![4]));
+
+		assert_eq!(overlay.iter_by_last_extrinsic().collect::<Vec<_>>(), vec![
+			(0, vec![&[2][..]]),
+			(1, vec![&[3][..]]),
+			(2, vec![&[1][..], &[4][..]]),
+			(NO_EXTRINSIC_INDEX, vec![&[100][..]]),
+		]);
+
+		overlay.rollback_transaction().unwrap();
+
+		assert_eq!(overlay.iter_by_last_extrinsic().collect::<Vec<_>>(), vec![
+			(0, vec![&[1][..], &[2][..]]),
+			(1, vec![&[3][..]]),
+			(NO_EXTRINSIC_INDEX, vec![&[100][..]]),
+		]);
+	}
+
+	#[test]
+	fn commit_transaction_with_keys_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.commit_transaction_with_keys(), Err(NoOpenTransaction));
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![1], None);
+		overlay.set_child_storage(&ChildInfo::new_default(b"Child1"), vec![4], Some(vec![4]));
+
+		assert_eq!(overlay.commit_transaction_with_keys(), Ok(vec![vec![1], vec![2]]));
+		assert_eq!(overlay.transaction_depth(), 1);
+		assert_eq!(overlay.commit_transaction_with_keys(), Ok(vec![vec![1], vec![2], vec![3]]));
+		assert_eq!(overlay.transaction_depth(), 0);
+		assert_eq!(overlay.storage(&[1]), Some(None));
+	}
+
+	#[test]
+	fn storage_hash_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1, 2, 3]));
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(
+			overlay.storage_hash::<Blake2Hasher>(&[1]),
+			Some(Some(Blake2Hasher::hash(overlay.storage(&[1]).unwrap().unwrap()))),
+		);
+		assert_eq!(overlay.storage_hash::<Blake2Hasher>(&[2]), Some(None));
+		assert_eq!(overlay.storage_hash::<Blake2Hasher>(&[3]), None);
+	}
+
+	#[test]
+	fn suspended_extrinsic_tracking_records_no_extrinsics() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		overlay.suspend_extrinsic_tracking();
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+
+		overlay.resume_extrinsic_tracking();
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		assert_extrinsics(&overlay.top, vec![1], vec![0]);
+		assert_extrinsics(&overlay.top, vec![2], vec![]);
+		assert_extrinsics(&overlay.top, vec![3], vec![2]);
+	}
+
+	#[test]
+	fn highest_extrinsic_index_ignores_writes_outside_extrinsics() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.highest_extrinsic_index(), None);
+
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.highest_extrinsic_index(), None);
+
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_extrinsic_index(5);
+		overlay.set_child_storage(&child_info, vec![4], Some(vec![4]));
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![5], Some(vec![5]));
+		assert_eq!(overlay.highest_extrinsic_index(), Some(5));
+
+		overlay.set_collect_extrinsics(false);
+		assert_eq!(overlay.highest_extrinsic_index(), None);
+	}
+
+	#[test]
+	fn without_changes_trie_records_no_extrinsics() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		let result = overlay.without_changes_trie(|overlay| {
+			overlay.set_extrinsic_index(1);
+			overlay.set_storage(vec![2], Some(vec![2]));
+			42
+		});
+		assert_eq!(result, 42);
+
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		assert_extrinsics(&overlay.top, vec![1], vec![0]);
+		assert_extrinsics(&overlay.top, vec![2], vec![]);
+		assert_extrinsics(&overlay.top, vec![3], vec![2]);
+	}
+
+	#[test]
+	fn without_changes_trie_resumes_tracking_on_panic() {
+		use std::panic::{catch_unwind, AssertUnwindSafe};
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(0);
+
+		let panicked = catch_unwind(AssertUnwindSafe(|| {
+			overlay.without_changes_trie(|overlay| {
+				overlay.set_storage(vec![1], Some(vec![1]));
+				panic!("aborted");
+			})
+		}));
+		assert!(panicked.is_err());
+
+		overlay.set_storage(vec![2], Some(vec![2]));
+		assert_extrinsics(&overlay.top, vec![1], vec![]);
+		assert_extrinsics(&overlay.top, vec![2], vec![0]);
+
+		// tracking suspended by the caller stays suspended
+		overlay.suspend_extrinsic_tracking();
+		overlay.without_changes_trie(|_| ());
+		overlay.set_storage(vec![3], Some(vec![3]));
+		assert_extrinsics(&overlay.top, vec![3], vec![]);
+	}
+
+	#[test]
+	fn committed_changes_render_as_json() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.committed_to_json(), r#"{"top":{},"children":{}}"#);
+
+		overlay.set_storage(vec![0x02], Some(vec![0xbe, 0xef]));
+		overlay.set_storage(vec![0x01], None);
+		overlay.set_child_storage(&child_info, vec![0x03], Some(vec![0x04]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![0x05], Some(vec![0x06]));
+		overlay.set_child_storage(&ChildInfo::new_default(b"Child2"), vec![0x07], None);
+
+		assert_eq!(
+			overlay.committed_to_json(),
+			r#"{"top":{"0x01":null,"0x02":"0xbeef"},"children":{"0x4368696c6431":{"0x03":"0x04"}}}"#,
+		);
+	}
+
+	#[test]
+	fn storage_committed_and_current_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.storage_committed_and_current(&[1]), (None, None));
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		assert_eq!(
+			overlay.storage_committed_and_current(&[1]),
+			(Some(Some(&[1][..])), Some(Some(&[1][..]))),
+		);
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.set_storage(vec![2], Some(vec![3]));
+		overlay.set_storage(vec![3], Some(vec![4]));
+
+		assert_eq!(
+			overlay.storage_committed_and_current(&[1]),
+			(Some(Some(&[1][..])), Some(Some(&[2][..]))),
+		);
+		assert_eq!(overlay.storage_committed_and_current(&[2]), (Some(None), Some(Some(&[3][..]))));
+		assert_eq!(overlay.storage_committed_and_current(&[3]), (None, Some(Some(&[4][..]))));
+	}
+
+	#[test]
+	fn imported_extrinsics_are_preserved() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		overlay.start_transaction();
+		overlay.set_storage_with_extrinsics(vec![1], Some(vec![2]), vec![3, 7].into_iter().collect());
+		overlay.set_storage_with_extrinsics(vec![2], None, vec![5].into_iter().collect());
+		overlay.set_storage_with_extrinsics(vec![2], None, vec![4, 6].into_iter().collect());
+		assert_extrinsics(&overlay.top, vec![1], vec![1, 3, 7]);
+		assert_extrinsics(&overlay.top, vec![2], vec![4, 6]);
+		overlay.commit_transaction().unwrap();
+
+		assert_extrinsics(&overlay.top, vec![1], vec![1, 3, 7]);
+		assert_extrinsics(&overlay.top, vec![2], vec![4, 6]);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[2]), Some(None));
+	}
+
+	#[test]
+	fn child_key_limit_is_enforced() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let child_info = &child_info;
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_max_keys(child_info.storage_key().to_vec(), 2);
+
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![1], Some(vec![1])), Ok(()));
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![2], Some(vec![2])), Ok(()));
+		assert_eq!(
+			overlay.try_set_child_storage(child_info, vec![3], Some(vec![3])),
+			Err(ChildWriteError::KeyLimitExceeded),
+		);
+		assert_eq!(overlay.child_storage(child_info, &[3]), None);
+
+		// updates and deletions are still allowed
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![1], Some(vec![10])), Ok(()));
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![3], None), Ok(()));
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![2], None), Ok(()));
+
+		// a deleted key frees up room
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![3], Some(vec![3])), Ok(()));
+		assert_eq!(overlay.child_storage(child_info, &[3]), Some(Some(&[3][..])));
+
+		// other children are not affected
+		let other = ChildInfo::new_default(b"Child2");
+		for i in 0..4 {
+			assert_eq!(overlay.try_set_child_storage(&other, vec![i], Some(vec![i])), Ok(()));
+		}
+	}
+
+	#[test]
+	fn child_key_limit_follows_rollback_and_clear() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let child_info = &child_info;
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_max_keys(child_info.storage_key().to_vec(), 2);
+
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![1], Some(vec![1])), Ok(()));
+		overlay.start_transaction();
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![2], Some(vec![2])), Ok(()));
+		overlay.set_child_storage(child_info, vec![1], None);
+		overlay.rollback_transaction().unwrap();
+
+		// only [1] is live again
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![2], Some(vec![2])), Ok(()));
+		assert_eq!(
+			overlay.try_set_child_storage(child_info, vec![3], Some(vec![3])),
+			Err(ChildWriteError::KeyLimitExceeded),
+		);
+
+		overlay.clear_child_storage(child_info);
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![3], Some(vec![3])), Ok(()));
+		assert_eq!(overlay.try_set_child_storage(child_info, vec![4], Some(vec![4])), Ok(()));
+		assert_eq!(
+			overlay.try_set_child_storage(child_info, vec![5], Some(vec![5])),
+			Err(ChildWriteError::KeyLimitExceeded),
+		);
+	}
+
+	#[test]
+	fn transform_prefix_is_transactional() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1, 1], Some(vec![1]));
+		overlay.set_storage(vec![1, 2], Some(vec![2]));
+		overlay.set_storage(vec![1, 3], None);
+		overlay.set_storage(vec![2, 1], Some(vec![3]));
+
+		overlay.start_transaction();
+		let count = overlay.transform_prefix(&[1], |key, value| match value {
+			Some(mut value) if key != [1, 2] => {
+				value.push(0);
+				Some(value)
+			},
+			Some(_) => None,
+			None => Some(vec![0]),
+		});
+		assert_eq!(count, 3);
+		assert_eq!(overlay.storage(&[1, 1]), Some(Some(&[1, 0][..])));
+		assert_eq!(overlay.storage(&[1, 2]), Some(None));
+		assert_eq!(overlay.storage(&[1, 3]), Some(Some(&[0][..])));
+		assert_eq!(overlay.storage(&[2, 1]), Some(Some(&[3][..])));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[1, 1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[1, 2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[1, 3]), Some(None));
+		assert_eq!(overlay.storage(&[2, 1]), Some(Some(&[3][..])));
+	}
+
+	#[test]
+	fn child_transform_prefix_is_transactional() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.child_transform_prefix(b"Child1", &[1], |_, _| unreachable!()), 0);
+
+		overlay.set_child_storage(&child_info, vec![1, 1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1, 2], None);
+		overlay.set_child_storage(&child_info, vec![2, 1], Some(vec![3]));
+		overlay.set_storage(vec![1, 3], Some(vec![4]));
+
+		overlay.start_transaction();
+		let count = overlay.child_transform_prefix(b"Child1", &[1], |_, value| match value {
+			Some(mut value) => {
+				value.push(0);
+				Some(value)
+			},
+			None => Some(vec![0]),
+		});
+		assert_eq!(count, 2);
+		assert_eq!(overlay.child_storage(&child_info, &[1, 1]), Some(Some(&[1, 0][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[1, 2]), Some(Some(&[0][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[2, 1]), Some(Some(&[3][..])));
+		assert_eq!(overlay.storage(&[1, 3]), Some(Some(&[4][..])));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.child_storage(&child_info, &[1, 1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[1, 2]), Some(None));
+	}
+
+	#[test]
+	fn try_clear_child_storage_does_not_create_child() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let other = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![3], None);
+
+		assert_eq!(overlay.try_clear_child_storage(other.storage_key()), Err(UnknownChild));
+		assert!(overlay.child_changes(other.storage_key()).is_none());
+
+		assert_eq!(overlay.try_clear_child_storage(child_info.storage_key()), Ok(2));
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(None));
+		assert_eq!(overlay.child_storage(&child_info, &[2]), Some(None));
+
+		overlay.clear_child_storage(&other);
+		assert!(overlay.child_changes(other.storage_key()).is_some());
+	}
+
+	impl BackendIter for BTreeMap<StorageKey, StorageValue> {
+		fn iter_prefix<'a>(&'a self, prefix: &[u8])
+			-> Box<dyn Iterator<Item = (StorageKey, StorageValue)> + 'a>
+		{
+			let prefix = prefix.to_vec();
+			Box::new(self.range(prefix.clone()..)
+				.take_while(move |(key, _)| key.starts_with(&prefix))
+				.map(|(key, value)| (key.clone(), value.clone())))
+		}
+	}
+
+	#[test]
+	fn merged_iter_merges_overlay_over_backend() {
+		let backend: BTreeMap<StorageKey, StorageValue> = vec![
+			(vec![0], vec![0]),
+			(vec![1, 1], vec![1]),
+			(vec![1, 3], vec![3]),
+			(vec![1, 5], vec![5]),
+			(vec![2], vec![2]),
+		].into_iter().collect();
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![10]));
+		overlay.set_storage(vec![1, 2], Some(vec![12]));
+		overlay.set_storage(vec![1, 3], Some(vec![13]));
+		overlay.set_storage(vec![1, 4], None);
+		overlay.set_storage(vec![1, 5], None);
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		let merged: Vec<_> = overlay.merged_iter(&backend, &[1]).collect();
+		assert_eq!(merged, vec![
+			(vec![1], vec![10]),
+			(vec![1, 1], vec![1]),
+			(vec![1, 2], vec![12]),
+			(vec![1, 3], vec![13]),
+		]);
+
+		let merged: Vec<_> = overlay.merged_iter(&backend, &[]).map(|(k, _)| k).collect();
+		assert_eq!(merged, vec![
+			vec![0], vec![1], vec![1, 1], vec![1, 2], vec![1, 3], vec![2], vec![3],
+		]);
+
+		let empty = BTreeMap::new();
+		let merged: Vec<_> = overlay.merged_iter(&empty, &[1]).map(|(k, _)| k).collect();
+		assert_eq!(merged, vec![vec![1], vec![1, 2], vec![1, 3]]);
+	}
+
+	#[test]
+	fn set_child_storage_value_works() {
+		let mut overlay = OverlayedChanges::default();
+		let prefixed = b":child_storage:default:Child1".to_vec();
+		assert_eq!(
+			overlay.set_child_storage_value(vec![], vec![1], Some(vec![1])),
+			Err(InvalidChildKeyspace),
+		);
+		assert_eq!(
+			overlay.set_child_storage_value(prefixed, vec![1], Some(vec![1])),
+			Err(InvalidChildKeyspace),
+		);
+		assert!(overlay.is_empty());
+
+		assert_eq!(
+			overlay.set_child_storage_value(b"Child1".to_vec(), vec![1], Some(vec![1])),
+			Ok(()),
+		);
+		let child_info = ChildInfo::new_default(b"Child1");
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.child_changes(b"Child1").map(|(_, info)| info.clone()), Some(child_info));
+	}
+
+	#[test]
+	fn into_committed_top_matches_drained_top() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![1], None);
+		overlay.set_child_storage(&child_info, vec![3], Some(vec![3]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.commit_transaction().unwrap();
+
+		let top: Vec<_> = overlay.clone().into_committed_top().collect();
+		let drained: Vec<_> = overlay.drain_committed().0.collect();
+		assert_eq!(top, drained);
+		assert_eq!(top, vec![(vec![1], None), (vec![2], Some(vec![2])), (vec![3], Some(vec![3]))]);
+	}
+
+	#[test]
+	fn storage_state_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(overlay.storage_state(&[1]), OverlayState::Present(&[1]));
+		assert_eq!(overlay.storage_state(&[2]), OverlayState::Deleted);
+		assert_eq!(overlay.storage_state(&[3]), OverlayState::Unknown);
+	}
+
+	#[test]
+	fn rollback_transactions_reports_remainder() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		for i in 2..5 {
+			overlay.start_transaction();
+			overlay.set_storage(vec![1], Some(vec![i]));
+		}
+
+		assert_eq!(overlay.rollback_transactions(2), Ok(()));
+		assert_eq!(overlay.transaction_depth(), 1);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[2][..])));
+
+		assert_eq!(overlay.rollback_transactions(4), Err(3));
+		assert_eq!(overlay.transaction_depth(), 0);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+
+		assert_eq!(overlay.rollback_transactions(0), Ok(()));
+	}
+
+	#[test]
+	fn child_root_inputs_only_include_changed_children() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let child3 = ChildInfo::new_default(b"Child3");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child1, vec![2], None);
+		overlay.clear_child_storage(&child2);
+		overlay.start_transaction();
+		overlay.set_child_storage(&child3, vec![3], Some(vec![3]));
+
+		let mut inputs: Vec<(&[u8], Vec<_>)> = overlay.child_root_inputs()
+			.map(|(storage_key, changes)| (storage_key, changes.collect()))
+			.collect();
+		inputs.sort();
+		assert_eq!(inputs, vec![
+			(&b"Child1"[..], vec![(&[1][..], Some(&[1][..])), (&[2][..], None)]),
+			(&b"Child3"[..], vec![(&[3][..], Some(&[3][..]))]),
+		]);
+	}
+
+	#[test]
+	fn max_transaction_depth_is_tracked() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.max_transaction_depth(), 0);
+
+		overlay.start_transaction();
+		overlay.start_transaction();
+		overlay.start_transaction();
+		assert_eq!(overlay.max_transaction_depth(), 3);
+		overlay.rollback_transactions(2).unwrap();
+		overlay.start_transaction();
+		overlay.rollback_transactions(2).unwrap();
+		assert_eq!(overlay.max_transaction_depth(), 3);
+
+		overlay.start_transaction();
+		overlay.reset_stats();
+		assert_eq!(overlay.max_transaction_depth(), 1);
+	}
+
+	#[test]
+	fn touched_keys_include_deleted_keys() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(overlay.touched_keys().collect::<Vec<_>>(), vec![&[1][..], &[2][..]]);
+		let live: Vec<_> = overlay.changes()
+			.filter(|(_, v)| v.value().is_some())
+			.map(|(k, _)| k.as_slice())
+			.collect();
+		assert_eq!(live, vec![&[1][..]]);
+	}
+
+	#[test]
+	fn with_transaction_commits_or_rolls_back() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		let r = overlay.with_transaction(|overlay| {
+			overlay.set_storage(vec![1], Some(vec![2]));
+			TransactionOutcome::Commit(2)
+		});
+		assert_eq!(r, 2);
+		assert_eq!(overlay.transaction_depth(), 0);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[2][..])));
+
+		let r = overlay.with_transaction(|overlay| {
+			overlay.set_storage(vec![1], Some(vec![3]));
+			overlay.set_storage(vec![2], Some(vec![3]));
+			TransactionOutcome::Rollback(3)
+		});
+		assert_eq!(r, 3);
+		assert_eq!(overlay.transaction_depth(), 0);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[2]), None);
+	}
+
+	#[test]
+	fn with_transaction_rolls_back_on_panic() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+			overlay.with_transaction(|overlay| -> TransactionOutcome<()> {
+				overlay.set_storage(vec![1], Some(vec![2]));
+				panic!("inside transaction")
+			})
+		}));
+		assert!(result.is_err());
+		assert_eq!(overlay.transaction_depth(), 0);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+	}
+
+	#[test]
+	fn changes_trie_entry_count_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.changes_trie_entry_count(), 0);
+
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], None);
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		// [1] => {1, 2}, [2] => {1}, child [1] => {2}, EXTRINSIC_INDEX is not attributed
+		assert_eq!(overlay.changes_trie_entry_count(), 4);
+
+		overlay.set_collect_extrinsics(false);
+		assert_eq!(overlay.changes_trie_entry_count(), 0);
+	}
+
+	#[test]
+	fn remove_storage_returns_prior_value() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		let mut expected = overlay.clone();
+		expected.set_storage(vec![1], None);
+		expected.set_storage(vec![2], None);
+		expected.set_child_storage(&child_info, vec![1], None);
+
+		assert_eq!(overlay.remove_storage(vec![1]), Some(Some(vec![1])));
+		assert_eq!(overlay.remove_storage(vec![1]), Some(None));
+		assert_eq!(overlay.remove_storage(vec![2]), None);
+		assert_eq!(overlay.remove_child_storage(&child_info, vec![1]), Ok(Some(Some(vec![1]))));
+		assert_eq!(
+			overlay.remove_child_storage(&ChildInfo::new_default(b""), vec![1]),
+			Err(InvalidChildKeyspace),
+		);
+
+		assert_eq!(
+			overlay.changes().map(|(k, v)| (k.clone(), v.value().cloned())).collect::<Vec<_>>(),
+			expected.changes().map(|(k, v)| (k.clone(), v.value().cloned())).collect::<Vec<_>>(),
+		);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(None));
+	}
+
+	#[test]
+	fn recorded_operations_replay_to_same_overlay() {
+		type Visible = Vec<(StorageKey, Option<StorageValue>, BTreeSet<u32>)>;
+		fn visible(overlay: &OverlayedChanges) -> (Visible, Vec<(StorageKey, Visible)>) {
+			let collect = |changes: &mut dyn Iterator<Item=(&StorageKey, &OverlayedValue)>| {
+				changes.map(|(k, v)| (k.clone(), v.value().cloned(), v.extrinsics())).collect()
+			};
+			let mut children: Vec<_> = overlay.children.iter()
+				.map(|(storage_key, (changeset, _))| (storage_key.clone(), collect(&mut changeset.changes())))
+				.collect();
+			children.sort();
+			(collect(&mut overlay.changes()), children)
+		}
+
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_recording();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(EXTRINSIC_INDEX.to_vec(), Some(1u32.encode()));
+		overlay.set_storage(vec![1, 1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage_ref(&[1, 2], Some(vec![2]));
+		overlay.clear_prefix(&[1]);
+		overlay.rollback_transaction().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage_with_extrinsics(vec![2], Some(vec![2]), vec![5].into_iter().collect());
+		overlay.clear_child_prefix(&child_info, &[]);
+		assert_eq!(overlay.commit_transaction(), Ok(()));
+		assert!(overlay.commit_transaction().is_err());
+		overlay.set_storage(vec![3], None);
+		overlay.start_transaction();
+		overlay.set_storage(vec![1, 1], Some(vec![4]));
+		overlay.drain_prospective();
+
+		let ops = overlay.take_recording();
+		assert_eq!(ops.len(), 16);
+		assert!(overlay.take_recording().is_empty());
+
+		let replayed = OverlayedChanges::replay(&ops);
+		assert_eq!(visible(&replayed), visible(&overlay));
+		assert_eq!(replayed.transaction_depth(), overlay.transaction_depth());
+	}
+
+	#[test]
+	fn recorded_in_place_changes_replay_to_same_overlay() {
+		let backend = InMemoryBackend::<Blake2Hasher>::from(
+			vec![(vec![1], vec![9u8].encode())].into_iter().collect::<BTreeMap<_, _>>(),
+		);
+		let append = |overlay: &mut OverlayedChanges, key: &[u8], item: u8| {
+			let mut cache = StorageTransactionCache::default();
+			let mut ext = Ext::new(
+				overlay,
+				&mut cache,
+				&backend,
+				crate::changes_trie::disabled_state::<_, u64>(),
+				None,
+			);
+			ext.storage_append(key.to_vec(), item.encode());
+		};
+		let committed = |overlay: &OverlayedChanges| -> Vec<_> {
+			overlay.changes().map(|(k, v)| (k.clone(), v.value().cloned())).collect()
+		};
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_recording();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_strict_extrinsic_decoding(true);
+		overlay.set_create_missing_child_on_clear(false);
+		append(&mut overlay, &[1], 1);
+		overlay.start_transaction();
+		append(&mut overlay, &[1], 2);
+		overlay.value_mut_or_insert_with(&[2], Vec::new).extend_from_slice(&[3, 4]);
+		assert_eq!(overlay.commit_transaction(), Ok(()));
+		overlay.start_transaction();
+		append(&mut overlay, &[1], 3);
+		overlay.rollback_transaction().unwrap();
+		overlay.suspend_extrinsic_tracking();
+		overlay.value_mut_or_insert_with(&[3], Vec::new).push(5);
+		overlay.resume_extrinsic_tracking();
+		overlay.set_offchain_storage(b"offchain", Some(b"value"));
+		overlay.add_transaction_index(IndexOperation::Renew { extrinsic: 0, hash: vec![0] });
+		assert_eq!(committed(&overlay)[1..], [(vec![2], Some(vec![3, 4])), (vec![3], Some(vec![5]))]);
+
+		let ops = overlay.take_recording();
+		assert!(matches!(ops[..], [.., OverlayOp::ModifyStorage { .. }, OverlayOp::ResumeExtrinsicTracking, _, _]));
+		let mut replayed = OverlayedChanges::replay(&ops);
+		assert_eq!(committed(&replayed), committed(&overlay));
+		assert_eq!(replayed.offchain_drain_committed().count(), 1);
+		assert_eq!(replayed.transaction_index_ops.len(), 1);
+
+		let _ = overlay.take_and_reset();
+		overlay.value_mut_or_insert_with(&[4], Vec::new).push(6);
+		let ops = overlay.take_recording();
+		assert!(matches!(ops[..], [OverlayOp::TakeAndReset, OverlayOp::ModifyStorage { .. }]));
+		let replayed = OverlayedChanges::replay(&ops);
+		assert_eq!(committed(&replayed), committed(&overlay));
+	}
+
+	#[test]
+	fn empty_values_can_be_treated_as_deleted() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let committed = |treat_empty_as_deleted| {
+			let mut overlay = OverlayedChanges::default();
+			overlay.set_treat_empty_as_deleted(treat_empty_as_deleted);
+			overlay.set_storage(vec![1], Some(vec![]));
+			overlay.set_storage(vec![2], Some(vec![2]));
+			overlay.set_child_storage(&child_info, vec![1], Some(vec![]));
+			let child = overlay.child_storage(&child_info, &[1]).map(|v| v.map(<[u8]>::to_vec));
+			(overlay.into_committed_top().collect::<Vec<_>>(), child)
+		};
+
+		assert_eq!(committed(false), (
+			vec![(vec![1], Some(vec![])), (vec![2], Some(vec![2]))],
+			Some(Some(vec![])),
+		));
+		assert_eq!(committed(true), (
+			vec![(vec![1], None), (vec![2], Some(vec![2]))],
+			Some(None),
+		));
+	}
+
+	#[test]
+	fn copy_storage_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(3);
+		assert!(overlay.copy_storage(&[1], vec![10]));
+		assert!(!overlay.copy_storage(&[2], vec![20]));
+		assert!(!overlay.copy_storage(&[3], vec![30]));
+		assert_eq!(overlay.storage(&[10]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[20]), None);
+		assert_eq!(overlay.storage(&[30]), None);
+		assert_extrinsics(&overlay.top, vec![10], vec![3]);
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[10]), None);
+	}
+
+	#[test]
+	fn swap_storage_is_transactional() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![3], None);
+
+		overlay.start_transaction();
+		overlay.swap_storage(&[1], &[2]);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[1][..])));
+
+		overlay.swap_storage(&[2], &[3]);
+		assert_eq!(overlay.storage(&[2]), Some(None));
+		assert_eq!(overlay.storage(&[3]), Some(Some(&[1][..])));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[3]), Some(None));
+	}
+
+	#[test]
+	fn committed_changes_stream_round_trip() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let other = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![2], None);
+		overlay.set_child_storage(&other, vec![3], Some(vec![3]));
+
+		let mut buffer = Vec::new();
+		overlay.clone().stream_committed_to(&mut buffer).unwrap();
+		let (top, mut children) = OverlayedChanges::read_committed_from(&buffer[..]).unwrap();
+
+		let (expected_top, expected_children) = overlay.drain_committed();
+		let mut expected_children: ChildStorageCollection = expected_children
+			.map(|(storage_key, (changes, _))| (storage_key, changes.collect()))
+			.collect();
+		children.sort();
+		expected_children.sort();
+		assert_eq!(top, expected_top.collect::<StorageCollection>());
+		assert_eq!(children, expected_children);
+
+		assert!(OverlayedChanges::read_committed_from(&buffer[..buffer.len() - 1]).is_err());
+	}
+
+	#[test]
+	fn child_storage_state_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![2], None);
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		let storage_key = child_info.storage_key();
+		assert_eq!(overlay.child_storage_state(storage_key, &[1]), OverlayState::Present(&[1]));
+		assert_eq!(overlay.child_storage_state(storage_key, &[2]), OverlayState::Deleted);
+		assert_eq!(overlay.child_storage_state(storage_key, &[3]), OverlayState::Unknown);
+		assert_eq!(overlay.child_storage_state(b"Child2", &[1]), OverlayState::Unknown);
+	}
+
+	#[test]
+	fn clear_prefix_limited_makes_progress() {
+		let mut overlay = OverlayedChanges::default();
+		for i in 0..10 {
+			overlay.set_storage(vec![1, i], Some(vec![i]));
+		}
+		overlay.set_storage(vec![1, 10], None);
+		overlay.set_storage(vec![2], Some(vec![2]));
+
+		assert_eq!(overlay.clear_prefix_limited(&[1], 6), (6, false));
+		assert_eq!(overlay.storage(&[1, 5]), Some(None));
+		assert_eq!(overlay.storage(&[1, 6]), Some(Some(&[6][..])));
+
+		assert_eq!(overlay.clear_prefix_limited(&[1], 6), (4, true));
+		assert!((0..10).all(|i| overlay.storage(&[1, i]) == Some(None)));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+
+		assert_eq!(overlay.clear_prefix_limited(&[1], 6), (0, true));
+	}
+
+	#[test]
+	fn storage_len_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1, 2, 3]));
+		overlay.set_storage(vec![2], Some(vec![]));
+		overlay.set_storage(vec![3], None);
+
+		assert_eq!(overlay.storage_len(&[1]), Some(Some(3)));
+		assert_eq!(overlay.storage_len(&[2]), Some(Some(0)));
+		assert_eq!(overlay.storage_len(&[3]), Some(None));
+		assert_eq!(overlay.storage_len(&[4]), None);
+	}
+
+	#[test]
+	fn reset_stats_keeps_changes() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1, 2]));
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1, 2][..])));
+
+		overlay.reset_stats();
+		assert_eq!(*overlay.stats.writes_overlay.borrow(), 0);
+		assert_eq!(*overlay.stats.bytes_writes_overlay.borrow(), 0);
+		assert_eq!(*overlay.stats.reads_modified.borrow(), 0);
+		assert_eq!(overlay.max_transaction_depth(), 0);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1, 2][..])));
+		assert_eq!(*overlay.stats.reads_modified.borrow(), 1);
+	}
+
+	#[test]
+	fn emptied_child_keyspaces_works() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child1, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child2, vec![1], Some(vec![1]));
+		overlay.clear_child_storage(&ChildInfo::new_default(b"Child3"));
+		assert_eq!(overlay.emptied_child_keyspaces().count(), 0);
+
+		overlay.clear_child_storage(&child1);
+		assert_eq!(overlay.emptied_child_keyspaces().collect::<Vec<_>>(), vec![&b"Child1"[..]]);
+	}
+
+	#[test]
+	fn extrinsics_union_for_prefix_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1, 1], Some(vec![1]));
+		overlay.set_storage(vec![2, 1], Some(vec![1]));
+		overlay.set_extrinsic_index(3);
+		overlay.set_storage(vec![1, 1], Some(vec![3]));
+		overlay.set_storage(vec![1, 2], None);
+		overlay.set_extrinsic_index(5);
+		overlay.set_storage(vec![1, 2], Some(vec![5]));
+		overlay.set_storage(vec![2, 2], Some(vec![5]));
+		overlay.set_extrinsic_index(7);
+		overlay.set_storage(vec![2, 1], Some(vec![7]));
+
+		let union = |overlay: &OverlayedChanges, prefix: &[u8]| {
+			overlay.extrinsics_union_for_prefix(prefix).into_iter().collect::<Vec<_>>()
+		};
+		assert_eq!(union(&overlay, &[1]), vec![1, 3, 5]);
+		assert_eq!(union(&overlay, &[2]), vec![1, 5, 7]);
+		assert_eq!(union(&overlay, &[3]), Vec::<u32>::new());
+
+		overlay.set_collect_extrinsics(false);
+		assert_eq!(union(&overlay, &[1]), Vec::<u32>::new());
+	}
+
+	#[test]
+	fn resolve_falls_back_to_backend() {
+		let initial: BTreeMap<_, _> = vec![
+			(vec![1], vec![1]),
+			(vec![2], vec![2]),
+			(vec![3], vec![3]),
+		].into_iter().collect();
+		let backend = InMemoryBackend::<Blake2Hasher>::from(initial);
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![10]));
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(overlay.resolve(&backend, &[1]), Ok(Some(vec![10])));
+		assert_eq!(overlay.resolve(&backend, &[2]), Ok(None));
+		assert_eq!(overlay.resolve(&backend, &[3]), Ok(Some(vec![3])));
+		assert_eq!(overlay.resolve(&backend, &[4]), Ok(None));
+	}
+
+	#[test]
+	fn commit_transaction_counted_counts_changed_values() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![20]));
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_storage(vec![4], None);
+		overlay.start_transaction();
+		overlay.set_storage(vec![5], Some(vec![5]));
+		assert_eq!(overlay.commit_transaction_counted(), Ok(0));
+		assert_eq!(overlay.commit_transaction_counted(), Ok(4));
+		assert_eq!(overlay.commit_transaction_counted(), Err(NoOpenTransaction));
+	}
+
+	#[test]
+	fn dry_run_leaves_overlay_unchanged() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		let before = overlay.changes()
+			.map(|(k, v)| (k.clone(), v.value().cloned()))
+			.collect::<StorageCollection>();
+
+		let (result, writes) = overlay.dry_run(|overlay| {
+			overlay.set_storage(vec![3], Some(vec![3]));
+			overlay.set_storage(vec![1], None);
+			overlay.start_transaction();
+			overlay.set_storage(vec![4], Some(vec![4]));
+			overlay.commit_transaction().unwrap();
+			overlay.start_transaction();
+			overlay.set_storage(vec![5], Some(vec![5]));
+			overlay.rollback_transaction().unwrap();
+			overlay.storage(&[2]).flatten().map(<[u8]>::to_vec)
+		});
+
+		assert_eq!(result, Some(vec![2]));
+		assert_eq!(writes, vec![
+			(vec![1], None),
+			(vec![3], Some(vec![3])),
+			(vec![4], Some(vec![4])),
+		]);
+		assert_eq!(overlay.transaction_depth(), 0);
+		let after = overlay.changes()
+			.map(|(k, v)| (k.clone(), v.value().cloned()))
+			.collect::<StorageCollection>();
+		assert_eq!(after, before);
+	}
+
+	#[test]
+	fn committed_changes_keep_extrinsics() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.start_transaction();
+		overlay.set_storage(vec![100], Some(vec![101]));
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![3], Some(vec![4]));
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], Some(vec![6]));
+		overlay.set_child_storage(&child_info, vec![1], None);
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(3);
+		overlay.set_storage(vec![3], Some(vec![7]));
+		overlay.rollback_transaction().unwrap();
+		overlay.commit_transaction().unwrap();
+
+		let (top, children) = overlay.into_committed_with_extrinsics();
+		let top: Vec<_> = top
+			.filter(|(key, _)| key != EXTRINSIC_INDEX)
+			.map(|(key, value)| (key, value.value().cloned(), value.extrinsics().into_iter().collect()))
+			.collect();
+		assert_eq!(top, vec![
+			(vec![1], Some(vec![6]), vec![0, 2]),
+			(vec![3], Some(vec![4]), vec![1]),
+			(vec![100], Some(vec![101]), vec![NO_EXTRINSIC_INDEX]),
+		]);
+		let children: Vec<_> = children
+			.flat_map(|(_, (changes, _))| changes)
+			.map(|(key, value)| (key, value.extrinsics().into_iter().collect::<Vec<_>>()))
+			.collect();
+		assert_eq!(children, vec![(vec![1], vec![2])]);
+	}
+
+	#[test]
+	fn skipped_clear_of_missing_child_is_reported() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_create_missing_child_on_clear(false);
+
+		overlay.start_transaction();
+		overlay.clear_child_storage(&child_info);
+		assert!(overlay.child_info(b"Child1").is_none());
+		assert_eq!(overlay.cleared_children().collect::<Vec<_>>(), vec![&b"Child1"[..]]);
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.cleared_children().count(), 0);
+
+		overlay.clear_child_storage(&child_info);
+		assert_eq!(overlay.cleared_children().collect::<Vec<_>>(), vec![&b"Child1"[..]]);
+	}
+
+	#[test]
+	fn clearing_missing_child_respects_configuration() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.clear_child_storage(&child1);
+		overlay.clear_child_prefix(&child2, &[1]);
+		assert!(overlay.child_info(b"Child1").is_some());
+		assert!(overlay.child_info(b"Child2").is_some());
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_create_missing_child_on_clear(false);
+		overlay.clear_child_storage(&child1);
+		overlay.clear_child_prefix(&child2, &[1]);
+		assert!(overlay.child_info(b"Child1").is_none());
+		assert!(overlay.child_info(b"Child2").is_none());
+
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.clear_child_storage(&child1);
+		assert_eq!(overlay.child_storage(&child1, &[1]), Some(None));
+	}
+
+	#[test]
+	fn drain_prospective_keeps_committed_state() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![3]));
+		overlay.set_storage(vec![3], None);
+		overlay.set_storage(vec![4], Some(vec![4]));
+
+		assert_eq!(overlay.drain_prospective(), vec![
+			(vec![2], Some(vec![3])),
+			(vec![3], None),
+			(vec![4], Some(vec![4])),
+		]);
+		assert!(!overlay.has_prospective_changes());
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[3]), None);
+		assert_eq!(overlay.storage(&[4]), None);
+		assert_eq!(overlay.debug_check_invariants(), Ok(()));
+
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.drain_prospective(), vec![]);
+		assert_eq!(overlay.top.committed_changes().count(), 2);
+	}
+
+	#[test]
+	fn iter_values_sorted_orders_by_key() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![1], Some(vec![4]));
+		overlay.set_child_storage(&child_info, vec![9], Some(vec![9]));
+		overlay.set_child_storage(&child_info, vec![5], None);
+
+		assert_eq!(overlay.iter_values_sorted(None).collect::<Vec<_>>(), vec![
+			(&[1][..], Some(&[4][..])),
+			(&[2][..], None),
+			(&[3][..], Some(&[3][..])),
+		]);
+		assert_eq!(overlay.iter_values_sorted(Some(b"Child1")).collect::<Vec<_>>(), vec![
+			(&[5][..], None),
+			(&[9][..], Some(&[9][..])),
+		]);
+		assert_eq!(overlay.iter_values_sorted(Some(b"Child2")).count(), 0);
+	}
+
+	#[test]
+	fn size_deltas_compare_against_committed_values() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1; 4]));
+		overlay.set_storage(vec![2], Some(vec![2; 4]));
+		overlay.set_storage(vec![3], Some(vec![3; 4]));
+		overlay.set_storage(vec![4], Some(vec![4; 4]));
+		assert_eq!(overlay.size_deltas().count(), 0);
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1; 6]));
+		overlay.set_storage(vec![2], Some(vec![2; 1]));
+		overlay.set_storage(vec![3], None);
+		overlay.set_storage(vec![4], Some(vec![0; 4]));
+		overlay.set_storage(vec![5], Some(vec![5; 3]));
+		overlay.set_storage(vec![6], None);
+
+		let mut deltas: Vec<_> = overlay.size_deltas().collect();
+		deltas.sort();
+		assert_eq!(deltas, vec![(&[1][..], 2), (&[2][..], -3), (&[3][..], -4), (&[5][..], 3)]);
+
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.size_deltas().count(), 0);
+	}
+
+	#[test]
+	fn get_or_insert_with_writes_default_once() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(overlay.get_or_insert_with(vec![1], || unreachable!()), &[1]);
+		overlay.start_transaction();
+		assert_eq!(overlay.get_or_insert_with(vec![2], || vec![2]), &[2]);
+		assert_eq!(overlay.get_or_insert_with(vec![3], || vec![3]), &[3]);
+		assert_eq!(overlay.get_or_insert_with(vec![3], || vec![4]), &[3]);
+		assert_eq!(overlay.storage(&[3]), Some(Some(&[3][..])));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[2]), Some(None));
+		assert_eq!(overlay.storage(&[3]), None);
+	}
+
+	#[test]
+	fn value_equals_compares_visible_value() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1, 2]));
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(overlay.value_equals(&[1], Some(&[1, 2])), Some(true));
+		assert_eq!(overlay.value_equals(&[1], Some(&[1])), Some(false));
+		assert_eq!(overlay.value_equals(&[1], None), Some(false));
+		assert_eq!(overlay.value_equals(&[2], None), Some(true));
+		assert_eq!(overlay.value_equals(&[2], Some(&[])), Some(false));
+		assert_eq!(overlay.value_equals(&[3], None), None);
+	}
+
+	#[test]
+	fn drain_child_committed_keeps_open_transactions() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		assert!(overlay.drain_child_committed(b"Child1").is_none());
+
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child1, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child2, vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_child_storage(&child1, vec![2], None);
+		overlay.set_child_storage(&child1, vec![3], Some(vec![3]));
+
+		let drained: Vec<_> = overlay.drain_child_committed(b"Child1").unwrap().collect();
+		assert_eq!(drained, vec![(vec![1], Some(vec![1])), (vec![2], Some(vec![2]))]);
+		assert_eq!(overlay.child_storage(&child1, &[1]), None);
+		assert_eq!(overlay.child_storage(&child1, &[2]), Some(None));
+		assert_eq!(overlay.child_storage(&child1, &[3]), Some(Some(&[3][..])));
+		assert_eq!(overlay.child_storage(&child2, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.debug_check_invariants(), Ok(()));
+
+		overlay.commit_transaction().unwrap();
+		let drained: Vec<_> = overlay.drain_child_committed(b"Child1").unwrap().collect();
+		assert_eq!(drained, vec![(vec![2], None), (vec![3], Some(vec![3]))]);
+		assert_eq!(overlay.child_info(b"Child1"), None);
+	}
+
+	#[test]
+	fn with_base_reads_committed_base_values() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut base = OverlayedChanges::default();
+		base.set_storage(vec![1], Some(vec![1]));
+		base.set_storage(vec![2], Some(vec![2]));
+		base.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		base.start_transaction();
+		base.set_storage(vec![3], Some(vec![3]));
+
+		let mut overlay = OverlayedChanges::with_base(&base);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[3]), None);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+
+		overlay.set_storage(vec![1], None);
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![2]));
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[2][..])));
+
+		assert_eq!(base.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(base.storage(&[4]), None);
+		assert_eq!(base.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.changes().count(), 3);
+	}
+
+	#[test]
+	fn with_base_is_seen_by_every_read() {
+		fn assert_send<T: Send>(_: &T) {}
+
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut base = OverlayedChanges::default();
+		base.set_storage(vec![1], Some(vec![1]));
+		base.set_storage(vec![3], Some(vec![3]));
+		base.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		let mut overlay = OverlayedChanges::with_base(&base);
+		assert_send(&overlay);
+		overlay.set_storage(vec![2], Some(vec![2]));
+		let keys: Vec<_> = overlay.changes().map(|(key, _)| key.clone()).collect();
+		assert_eq!(keys, vec![vec![1], vec![2], vec![3]]);
+		let keys: Vec<_> = overlay.iter_after(&[1]).map(|(key, _)| key.to_vec()).collect();
+		assert_eq!(keys, vec![vec![2], vec![3]]);
+		assert_eq!(overlay.children().count(), 1);
+
+		overlay.start_transaction();
+		overlay.clear_prefix(&[]);
+		overlay.clear_child_storage(&child_info);
+		assert_eq!(overlay.storage(&[3]), Some(None));
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(None));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[3]), Some(Some(&[3][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+
+		let (top, children) = overlay.drain_committed();
+		assert_eq!(top.collect::<Vec<_>>(), vec![
+			(vec![1], Some(vec![1])),
+			(vec![2], Some(vec![2])),
+			(vec![3], Some(vec![3])),
+		]);
+		let children: Vec<_> = children.map(|(key, (changes, _))| (key, changes.collect::<Vec<_>>())).collect();
+		assert_eq!(children, vec![(b"Child1".to_vec(), vec![(vec![1], Some(vec![1]))])]);
+		assert_eq!(base.storage(&[2]), None);
+	}
+
+	#[test]
+	fn cleared_children_are_reported() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let child3 = ChildInfo::new_default(b"Child3");
+		let mut overlay = OverlayedChanges::default();
+		for child_info in &[&child1, &child2, &child3] {
+			overlay.set_child_storage(child_info, vec![1], Some(vec![1]));
+		}
+		overlay.set_child_storage(&child2, vec![1], None);
+		assert_eq!(overlay.cleared_children().count(), 0);
+
+		overlay.clear_child_storage(&child3);
+		overlay.start_transaction();
+		overlay.clear_child_storage(&child1);
+		overlay.set_child_storage(&child1, vec![2], Some(vec![2]));
+		assert_eq!(overlay.cleared_children().collect::<Vec<_>>(), vec![&b"Child1"[..], b"Child3"]);
+
+		overlay.start_transaction();
+		assert_eq!(overlay.clear_child_all(b"Child2"), 0);
+		overlay.rollback_transaction().unwrap();
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.cleared_children().collect::<Vec<_>>(), vec![&b"Child1"[..], b"Child3"]);
+	}
+
+	#[test]
+	fn clears_left_open_by_runtime_are_not_reported() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.enter_runtime().unwrap();
+		overlay.start_transaction();
+		overlay.clear_child_storage(&child_info);
+		assert_eq!(overlay.cleared_children().count(), 1);
+
+		overlay.exit_runtime().unwrap();
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.cleared_children().count(), 0);
+	}
+
+	#[test]
+	fn scoped_transactions_work() {
+		let mut overlay = OverlayedChanges::default();
+		let outer = overlay.start_transaction_scoped();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		let inner = overlay.start_transaction_scoped();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.rollback_scoped(inner).unwrap();
+		overlay.commit_scoped(outer).unwrap();
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[2]), None);
+		assert_eq!(overlay.transaction_depth(), 0);
+	}
+
+	#[test]
+	fn scoped_transactions_reject_mismatched_tokens() {
+		let mut overlay = OverlayedChanges::default();
+		let outer = overlay.start_transaction_scoped();
+		let inner = overlay.start_transaction_scoped();
+		assert_eq!(overlay.commit_scoped(outer), Err(TxScopeError::TokenMismatch));
+		assert_eq!(overlay.transaction_depth(), 2);
+
+		// A transaction started without a token is not closed by the enclosing token.
+		overlay.start_transaction();
+		assert_eq!(overlay.rollback_scoped(inner), Err(TxScopeError::TokenMismatch));
+		overlay.rollback_transaction().unwrap();
+
+		// Closing a scoped transaction without its token invalidates the token.
+		overlay.commit_transaction().unwrap();
+		overlay.start_transaction();
+		let stale = TxToken(1);
+		assert_eq!(overlay.commit_scoped(stale), Err(TxScopeError::TokenMismatch));
+		overlay.commit_transaction().unwrap();
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.commit_scoped(TxToken(0)), Err(TxScopeError::NoOpenTransaction));
+	}
+
+	#[test]
+	fn committed_child_storage_ignores_open_transactions() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_transaction();
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		assert_eq!(overlay.committed_child_storage(b"Child1", &[1]), None);
+		overlay.commit_transaction().unwrap();
+
+		overlay.start_transaction();
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![2], None);
+		assert_eq!(overlay.committed_child_storage(b"Child1", &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.committed_child_storage(b"Child1", &[2]), None);
+		assert_eq!(overlay.committed_child_storage(b"Child2", &[1]), None);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[2][..])));
+	}
+
+	#[test]
+	fn resolve_and_cache_reads_backend_once() {
+		let initial: BTreeMap<_, _> = vec![(b"key".to_vec(), b"backend".to_vec())].into_iter().collect();
+		let backend = InMemoryBackend::<Blake2Hasher>::from(initial);
+		let empty = InMemoryBackend::<Blake2Hasher>::from(BTreeMap::new());
+		let mut overlay = OverlayedChanges::default();
+
+		assert_eq!(overlay.resolve_and_cache(&backend, b"key").unwrap(), Some(b"backend".to_vec()));
+		assert_eq!(overlay.resolve_and_cache(&empty, b"key").unwrap(), Some(b"backend".to_vec()));
+		assert_eq!(overlay.resolve_and_cache(&backend, b"other").unwrap(), None);
+		assert_eq!(overlay.storage(b"key"), None);
+
+		overlay.start_transaction();
+		overlay.set_storage(b"key".to_vec(), None);
+		assert_eq!(overlay.resolve_and_cache(&backend, b"key").unwrap(), None);
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.resolve_and_cache(&empty, b"key").unwrap(), Some(b"backend".to_vec()));
+
+		let (top, ..) = overlay.take_and_reset();
+		assert_eq!(top.count(), 0);
+		assert_eq!(overlay.resolve_and_cache(&empty, b"key").unwrap(), None);
+	}
+
+	#[test]
+	fn shrink_to_fit_releases_children() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&ChildInfo::new_default(b"Child1"), vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		for i in 0..256u32 {
+			overlay.set_child_storage(&ChildInfo::new_default(&i.encode()), vec![1], None);
+		}
+		overlay.rollback_transaction().unwrap();
+		overlay.clear_child_storage(&ChildInfo::new_default(b"Child2"));
+		let capacity = overlay.children.capacity();
+		assert_eq!(overlay.child_trie_count(), 2);
+
+		overlay.shrink_to_fit();
+		assert!(overlay.children.capacity() < capacity);
+		assert_eq!(overlay.child_trie_count(), 1);
+		assert_eq!(overlay.child_keys(b"Child1").count(), 1);
+	}
+
+	#[test]
+	fn commit_log_records_committed_changes() {
+		use std::{io, sync::{Arc, Mutex}};
+
+		#[derive(Clone, Default)]
+		struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+		impl io::Write for SharedLog {
+			fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+				self.0.lock().unwrap().write(buf)
+			}
+
+			fn flush(&mut self) -> io::Result<()> {
+				Ok(())
+			}
+		}
+
+		let child_info = ChildInfo::new_default(b"Child1");
+		let log = SharedLog::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_commit_log(log.clone());
+		overlay.set_storage(vec![9], Some(vec![9]));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1], None);
+		overlay.commit_transaction().unwrap();
+		assert!(log.0.lock().unwrap().is_empty());
+		overlay.commit_transaction().unwrap();
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], None);
+		overlay.commit_transaction().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.rollback_transaction().unwrap();
+
+		let _ = overlay.take_and_reset();
+		overlay.start_transaction();
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.commit_transaction().unwrap();
+		overlay.set_storage(vec![5], Some(vec![5]));
+		overlay.value_mut_or_insert_with(&[5], Vec::new).push(6);
+		overlay.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		let _ = overlay.take_and_reset();
+
+		let bytes = log.0.lock().unwrap().clone();
+		let mut input = &bytes[..];
+		let mut records = Vec::new();
+		while !input.is_empty() {
+			let record = Vec::<u8>::decode(&mut input).unwrap();
+			records.push(<(StorageCollection, ChildStorageCollection)>::decode(&mut &record[..]).unwrap());
+		}
+		assert_eq!(records, vec![
+			(
+				vec![(vec![1], Some(vec![1])), (vec![2], Some(vec![2])), (vec![9], Some(vec![9]))],
+				vec![(b"Child1".to_vec(), vec![(vec![1], None)])],
+			),
+			(vec![(vec![2], None)], vec![]),
+			(vec![(vec![4], Some(vec![4]))], vec![]),
+			(
+				vec![(vec![5], Some(vec![5, 6]))],
+				vec![(b"Child1".to_vec(), vec![(vec![2], Some(vec![2]))])],
+			),
+		]);
+		assert!(overlay.take_commit_log_error().is_none());
+	}
+
+	#[test]
+	fn commit_log_surfaces_write_errors() {
+		struct FailingLog;
+
+		impl std::io::Write for FailingLog {
+			fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+				Err(std::io::Error::new(std::io::ErrorKind::Other, "disk full"))
+			}
+
+			fn flush(&mut self) -> std::io::Result<()> {
+				Ok(())
+			}
+		}
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_commit_log(FailingLog);
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_transaction().unwrap();
+
+		assert_eq!(overlay.take_commit_log_error().unwrap().to_string(), "disk full");
+		assert!(overlay.take_commit_log_error().is_none());
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+	}
+
+	#[test]
+	fn apply_diff_can_be_rolled_back() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		overlay.start_transaction();
+		assert_eq!(
+			overlay.apply_diff(vec![(vec![2], Some(vec![2]))], vec![(Vec::new(), Vec::new())]),
+			Err(InvalidChildKeyspace),
+		);
+		assert_eq!(overlay.storage(&[2]), None);
+
+		overlay.apply_diff(
+			vec![(vec![1], None), (vec![2], Some(vec![2]))],
+			vec![(b"Child1".to_vec(), vec![(vec![1], Some(vec![10])), (vec![2], None)])],
+		).unwrap();
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[10][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[2]), Some(None));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[2]), None);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[2]), None);
+	}
+
+	#[test]
+	fn children_iter_sorted_is_ordered() {
+		let mut overlay = OverlayedChanges::default();
+		for storage_key in &[&b"Child3"[..], b"Child1", b"Child2"] {
+			let child_info = ChildInfo::new_default(storage_key);
+			overlay.set_child_storage(&child_info, vec![2], None);
+			overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		}
+
+		let children: Vec<_> = overlay.children_iter_sorted()
+			.map(|(storage_key, changes)| (storage_key, changes.collect::<Vec<_>>()))
+			.collect();
+		let changes = vec![(&[1][..], Some(&[1][..])), (&[2][..], None)];
+		assert_eq!(children, vec![
+			(&b"Child1"[..], changes.clone()),
+			(&b"Child2"[..], changes.clone()),
+			(&b"Child3"[..], changes),
+		]);
+	}
+
+	#[test]
+	fn key_tags_follow_overlayed_keys() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.tag_key(&[1], 1);
+		assert_eq!(overlay.key_tag(&[1]), None);
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.tag_key(&[1], 1);
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], None);
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.tag_key(&[1], 3);
+		overlay.tag_key(&[2], 2);
+		overlay.commit_transaction().unwrap();
+		assert_eq!((overlay.key_tag(&[1]), overlay.key_tag(&[2])), (Some(3), Some(2)));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.tag_key(&[3], 3);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.key_tag(&[3]), None);
+		assert_eq!(overlay.key_tag(&[1]), Some(3));
+
+		overlay.enter_runtime().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.tag_key(&[3], 3);
+		overlay.exit_runtime().unwrap();
+		assert_eq!(overlay.key_tag(&[3]), None);
+
+		let (top, ..) = overlay.take_and_reset();
+		assert_eq!(top.collect::<Vec<_>>(), vec![(vec![1], None), (vec![2], Some(vec![2]))]);
+		assert_eq!(overlay.key_tag(&[2]), None);
+	}
+
+	#[test]
+	fn is_deleted_and_is_present_work() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![3], Some(vec![]));
+
+		assert_eq!((overlay.is_deleted(&[1]), overlay.is_present(&[1])), (false, true));
+		assert_eq!((overlay.is_deleted(&[2]), overlay.is_present(&[2])), (true, false));
+		assert_eq!((overlay.is_deleted(&[3]), overlay.is_present(&[3])), (false, true));
+		assert_eq!((overlay.is_deleted(&[4]), overlay.is_present(&[4])), (false, false));
+	}
+
+	#[test]
+	fn set_child_storage_many_matches_single_writes() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let items = vec![
+			(vec![1], Some(vec![1])),
+			(vec![2], None),
+			(vec![1], Some(vec![3])),
+			(vec![4], Some(vec![4])),
+		];
+
+		let mut single = OverlayedChanges::default();
+		single.set_collect_extrinsics(true);
+		single.set_extrinsic_index(2);
+		single.start_transaction();
+		for (key, value) in items.clone() {
+			single.set_child_storage(&child_info, key, value);
+		}
+
+		let mut batch = OverlayedChanges::default();
+		batch.set_collect_extrinsics(true);
+		batch.set_extrinsic_index(2);
+		batch.start_transaction();
+		batch.set_child_storage_many(b"Child1".to_vec(), items).unwrap();
+
+		let changes = |overlay: &OverlayedChanges| overlay.child_changes(b"Child1").unwrap().0
+			.map(|(key, value)| (key.clone(), value.value().cloned(), value.extrinsics()))
+			.collect::<Vec<_>>();
+		assert_eq!(changes(&batch), changes(&single));
+		batch.rollback_transaction().unwrap();
+		assert_eq!(batch.child_info(b"Child1"), None);
+		assert_eq!(
+			batch.set_child_storage_many(Vec::new(), vec![(vec![1], None)]),
+			Err(InvalidChildKeyspace),
+		);
+	}
+
+	#[test]
+	fn apply_to_values_while_stops_early() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		let mut visited = Vec::new();
+		let completed = overlay.apply_to_values_while(None, |key, value| {
+			visited.push((key.to_vec(), value.map(|v| v.to_vec())));
+			key != [2]
+		});
+		assert!(!completed);
+		assert_eq!(visited, vec![(vec![1], Some(vec![1])), (vec![2], None)]);
+
+		let mut visited = Vec::new();
+		assert!(overlay.apply_to_values_while(Some(b"Child1"), |key, _| {
+			visited.push(key.to_vec());
+			true
+		}));
+		assert_eq!(visited, vec![vec![1]]);
+		assert!(overlay.apply_to_values_while(Some(b"Child2"), |_, _| false));
+	}
+
+	#[test]
+	fn changes_summary_counts_by_kind() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.changes_summary(), ChangesSummary::default());
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![3], None);
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child2, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child2, vec![2], None);
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], None);
+
+		assert_eq!(overlay.changes_summary(), ChangesSummary {
+			sets: 1,
+			deletes: 2,
+			child_sets: 2,
+			child_deletes: 1,
+		});
+	}
+
+	#[test]
+	fn rename_child_keyspace_works() {
+		let from = ChildInfo::new_default(b"Child1");
+		let to = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		assert!(!overlay.rename_child_keyspace(b"Child1", b"Child2".to_vec()));
+
+		overlay.set_child_storage(&from, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&from, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&from, vec![3], None);
+
+		overlay.start_transaction();
+		assert!(overlay.rename_child_keyspace(b"Child1", b"Child2".to_vec()));
+		assert_eq!(overlay.child_storage(&to, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.child_storage(&to, &[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.child_storage(&to, &[3]), None);
+		assert_eq!(overlay.child_keys(b"Child1").count(), 0);
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.child_info(b"Child2"), None);
+		assert_eq!(overlay.child_keys(b"Child1").collect::<Vec<_>>(), vec![&[1][..], &[2][..]]);
+	}
+
+	#[test]
+	fn rename_child_keyspace_rejects_invalid_target() {
+		let from = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&from, vec![1], Some(vec![1]));
+
+		assert!(!overlay.rename_child_keyspace(b"Child1", Vec::new()));
+		assert!(!overlay.rename_child_keyspace(b"Child1", b":child_storage:default:Child2".to_vec()));
+		assert_eq!(overlay.child_trie_count(), 1);
+		assert_eq!(overlay.child_storage(&from, &[1]), Some(Some(&[1][..])));
+	}
+
+	#[test]
+	fn rename_child_keyspace_rejects_existing_target() {
+		let from = ChildInfo::new_default(b"Child1");
+		let to = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&from, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&to, vec![2], Some(vec![2]));
+
+		assert!(!overlay.rename_child_keyspace(b"Child1", b"Child2".to_vec()));
+		assert_eq!(overlay.child_storage(&from, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.child_storage(&to, &[1]), None);
+		assert_eq!(overlay.child_storage(&to, &[2]), Some(Some(&[2][..])));
+	}
+
+	#[test]
+	fn prefix_exists_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert!(!overlay.prefix_exists(&[]));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2, 1], None);
+		overlay.set_storage(vec![2, 2], None);
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		assert!(overlay.prefix_exists(&[]));
+		assert!(overlay.prefix_exists(&[1]));
+		assert!(!overlay.prefix_exists(&[2]));
+		assert!(!overlay.prefix_exists(&[4]));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![2, 2, 2], Some(vec![]));
+		assert!(overlay.prefix_exists(&[2]));
+		overlay.set_storage(vec![1], None);
+		assert!(!overlay.prefix_exists(&[1]));
+		overlay.rollback_transaction().unwrap();
+		assert!(overlay.prefix_exists(&[1]));
+		assert!(!overlay.prefix_exists(&[2]));
+	}
+
+	#[test]
+	fn take_and_reset_keeps_config() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_treat_empty_as_deleted(true);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_offchain_storage(b"off", Some(b"chain"));
+		overlay.add_transaction_index(IndexOperation::Renew { extrinsic: 0, hash: vec![1] });
+
+		let (top, children, offchain, index_ops) = overlay.take_and_reset();
+		assert_eq!(top.collect::<Vec<_>>(), vec![(vec![1], Some(vec![1])), (vec![2], None)]);
+		assert_eq!(children.count(), 1);
+		assert_eq!(offchain.len(), 1);
+		assert_eq!(index_ops.len(), 1);
+		assert!(overlay.is_empty());
+		assert_eq!(overlay.offchain_drain_committed().count(), 0);
+		assert!(overlay.transaction_index_ops().is_empty());
+		assert_eq!(overlay.write_generation(), 0);
+
+		overlay.set_extrinsic_index(3);
+		overlay.set_storage(vec![1], Some(vec![]));
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_extrinsics(&overlay.top, vec![1], vec![3]);
+	}
+
+	#[test]
+	fn extrinsic_tracking_bytes_scales_with_extrinsics() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.extrinsic_tracking_bytes(), 0);
+
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		assert_eq!(overlay.extrinsic_tracking_bytes(), 3 * 4);
+
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], None);
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![2]));
+		assert_eq!(overlay.extrinsic_tracking_bytes(), 5 * 4);
+	}
+
+	#[test]
+	fn child_keys_skips_deleted_keys() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![2], None);
+		overlay.set_child_storage(&child_info, vec![3], Some(vec![3]));
+		overlay.start_transaction();
+		overlay.set_child_storage(&child_info, vec![1], None);
+		overlay.set_child_storage(&child_info, vec![4], Some(vec![]));
+
+		let keys: Vec<_> = overlay.child_keys(b"Child1").collect();
+		assert_eq!(keys, vec![&[3][..], &[4][..]]);
+		assert_eq!(overlay.child_keys(b"Child2").count(), 0);
+	}
+
+	#[test]
+	fn rollback_hook_receives_reverted_keys() {
+		use std::sync::{Arc, Mutex};
+
+		let reverted = Arc::new(Mutex::new(Vec::new()));
+		let mut overlay = OverlayedChanges::default();
+		let hook_reverted = reverted.clone();
+		overlay.set_rollback_hook(Box::new(move |keys| {
+			hook_reverted.lock().unwrap().push(keys.to_vec())
+		}));
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![1], None);
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(*reverted.lock().unwrap(), vec![vec![vec![1], vec![2]]]);
+
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.rollback_transaction(), Err(NoOpenTransaction));
+		assert_eq!(reverted.lock().unwrap().len(), 1);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+
+		overlay.start_transaction();
+		overlay.enter_runtime().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![5], Some(vec![5]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.set_storage(vec![5], None);
+		overlay.exit_runtime().unwrap();
+		assert_eq!(reverted.lock().unwrap()[1], vec![vec![4], vec![5]]);
+		assert_eq!(overlay.transaction_depth(), 1);
+	}
+
+	#[test]
+	fn panicking_rollback_hook_leaves_changesets_in_lockstep() {
+		use std::panic::{catch_unwind, AssertUnwindSafe};
+
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_rollback_hook(Box::new(|_| panic!("hook failed")));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1], None);
+
+		assert!(catch_unwind(AssertUnwindSafe(|| overlay.rollback_transaction())).is_err());
+		assert_eq!(overlay.transaction_depth(), 0);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.debug_check_invariants(), Ok(()));
+	}
+
+	#[test]
+	fn into_committed_pruned_drops_transient_keys() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![3], None);
+		overlay.set_child_storage(&child_info, vec![1], None);
+		overlay.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![3], None);
+
+		let (top, children) = overlay.into_committed_pruned(|child_info, key| match child_info {
+			None => key == [3],
+			Some(child_info) => child_info.storage_key() == b"Child1" && key == [1],
+		});
+		assert_eq!(top.collect::<Vec<_>>(), vec![(vec![1], Some(vec![1])), (vec![3], None)]);
+		let children: Vec<_> = children
+			.map(|(storage_key, (changes, _))| (storage_key, changes.collect::<Vec<_>>()))
+			.collect();
+		assert_eq!(children, vec![
+			(b"Child1".to_vec(), vec![(vec![1], None), (vec![2], Some(vec![2]))]),
+		]);
+	}
+
+	#[test]
+	fn strict_extrinsic_decoding_detects_malformed_index() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(EXTRINSIC_INDEX.to_vec(), Some(3u32.encode()));
+		overlay.set_strict_extrinsic_decoding(true);
+		assert_eq!(overlay.checked_extrinsic_index().unwrap(), Some(3));
+
+		overlay.set_storage(EXTRINSIC_INDEX.to_vec(), Some(vec![1]));
+		assert!(overlay.checked_extrinsic_index().is_err());
+
+		overlay.set_strict_extrinsic_decoding(false);
+		assert_eq!(overlay.checked_extrinsic_index().unwrap(), Some(NO_EXTRINSIC_INDEX));
+	}
+
+	#[test]
+	fn strict_extrinsic_decoding_records_error_on_write() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_strict_extrinsic_decoding(true);
+		overlay.set_storage(EXTRINSIC_INDEX.to_vec(), Some(vec![1]));
+		assert!(overlay.take_extrinsic_index_error().is_none());
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&ChildInfo::new_default(b"Child1"), vec![1], Some(vec![1]));
+		assert_extrinsics(&overlay.top, vec![1], vec![NO_EXTRINSIC_INDEX]);
+		assert!(overlay.take_extrinsic_index_error().is_some());
+		assert!(overlay.take_extrinsic_index_error().is_none());
+
+		overlay.set_storage(EXTRINSIC_INDEX.to_vec(), Some(2u32.encode()));
+		assert!(overlay.take_extrinsic_index_error().is_some());
+		overlay.set_storage(vec![2], Some(vec![2]));
+		assert_extrinsics(&overlay.top, vec![2], vec![2]);
+		assert!(overlay.take_extrinsic_index_error().is_none());
+	}
+
+	#[test]
+	fn new_collecting_extrinsics_collects_extrinsics() {
+		let mut overlay = OverlayedChanges::new_collecting_extrinsics();
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_extrinsics(&overlay.top, vec![1], vec![1]);
+
+		let mut overlay = OverlayedChanges::new();
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_extrinsics(&overlay.top, vec![1], vec![]);
+		assert!(OverlayedChanges::new().is_empty());
+	}
+
+	#[test]
+	fn keys_changed_since_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![0], Some(vec![0]));
+		assert_eq!(overlay.write_generation(), 0);
+		assert_eq!(overlay.keys_changed_since(0).count(), 0);
+
+		overlay.set_track_write_generations(true);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![3, 1], Some(vec![3]));
+		let generation = overlay.write_generation();
+		assert_eq!(overlay.keys_changed_since(generation).count(), 0);
+
+		overlay.set_storage_ref(&[2], None);
+		overlay.start_transaction();
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(
+			overlay.keys_changed_since(generation).collect::<Vec<_>>(),
+			vec![&[2][..], &[4][..]],
+		);
+
+		let generation = overlay.write_generation();
+		overlay.clear_prefix(&[3]);
+		assert_eq!(overlay.keys_changed_since(generation).collect::<Vec<_>>(), vec![&[3, 1][..]]);
+		assert_eq!(overlay.keys_changed_since(0).count(), 4);
+
+		let generation = overlay.write_generation();
+		overlay.start_transaction();
+		overlay.enter_runtime().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![5], Some(vec![5]));
+		overlay.exit_runtime().unwrap();
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.keys_changed_since(generation).collect::<Vec<_>>(), vec![&[5][..]]);
+
+		let _ = overlay.drain_committed();
+		assert_eq!(overlay.keys_changed_since(0).count(), 0);
+	}
+
+	#[test]
+	fn merge_prospective_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![3], Some(vec![3]));
+
+		let mut other = OverlayedChanges::default();
+		other.set_storage(vec![4], Some(vec![4]));
+		other.start_transaction();
+		other.set_storage(vec![3], Some(vec![30]));
+		other.set_storage(vec![1], None);
+		other.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		overlay.merge_prospective(&other);
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[3]), Some(Some(&[30][..])));
+		assert_eq!(overlay.storage(&[4]), None);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[3]), None);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), None);
+	}
+
+	#[test]
+	fn debug_check_invariants_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.enter_runtime().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], None);
+		overlay.clear_child_storage(&child_info);
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![3]));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.debug_check_invariants(), Ok(()));
+		overlay.exit_runtime().unwrap();
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.debug_check_invariants(), Ok(()));
+
+		overlay.start_transaction();
+		overlay.children.get_mut(child_info.storage_key()).unwrap().0.start_transaction();
+		assert!(overlay.debug_check_invariants().is_err());
+	}
+
+	#[test]
+	fn sorted_top_changes_incremental_reports_changed_entries() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_storage(vec![5], None);
+		assert!(overlay.sorted_top.dirty.is_empty());
+		assert_eq!(overlay.sorted_top_changes_incremental().1, &[0, 1, 2][..]);
+		assert_eq!(overlay.sorted_top_changes_incremental().1, &[][..]);
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![0], Some(vec![0]));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![3], None);
+		let (sorted, changed) = overlay.sorted_top_changes_incremental();
+		assert_eq!(sorted, &[
+			(vec![0], Some(vec![0])),
+			(vec![1], Some(vec![1])),
+			(vec![3], None),
+			(vec![5], None),
+		][..]);
+		assert_eq!(changed, &[0, 2][..]);
+
+		overlay.rollback_transaction().unwrap();
+		let (sorted, changed) = overlay.sorted_top_changes_incremental();
+		assert_eq!(sorted, &[
+			(vec![1], Some(vec![1])),
+			(vec![3], Some(vec![3])),
+			(vec![5], None),
+		][..]);
+		assert_eq!(changed, &[1][..]);
+
+		overlay.clear_prefix(&[5]);
+		overlay.set_storage(vec![6], Some(vec![6]));
+		assert_eq!(overlay.sorted_top.dirty.len(), 2);
+		assert_eq!(overlay.sorted_top_changes_incremental().1, &[3][..]);
+		assert!(overlay.clone().sorted_top.entries.is_empty());
+
+		let _ = overlay.drain_committed();
+		assert!(!overlay.sorted_top.active);
+		assert!(overlay.sorted_top_changes_incremental().0.is_empty());
+	}
+
+	#[test]
+	fn sorted_top_changes_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_storage(vec![1, 2], None);
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+
+		assert_eq!(overlay.sorted_top_changes(), vec![
+			(&[1][..], Some(&[1][..])),
+			(&[1, 2][..], None),
+			(&[2][..], Some(&[2][..])),
+			(&[3][..], Some(&[3][..])),
+		]);
+	}
+
+	#[test]
+	fn child_keyspace_is_validated() {
+		assert_eq!(validate_child_keyspace(b"Child1"), Ok(()));
+		assert_eq!(validate_child_keyspace(b":child_storage:default:Child1"), Err(InvalidChildKeyspace));
+		assert_eq!(validate_child_keyspace(b":child_storage:"), Err(InvalidChildKeyspace));
+		assert_eq!(validate_child_keyspace(b""), Err(InvalidChildKeyspace));
+
+		let mut overlay = OverlayedChanges::default();
+		let valid = ChildInfo::new_default(b"Child1");
+		assert_eq!(overlay.try_set_child_storage(&valid, vec![1], Some(vec![1])), Ok(()));
+		for invalid in &[&b""[..], b":child_storage:default:Child1"] {
+			assert_eq!(
+				overlay.try_set_child_storage(&ChildInfo::new_default(invalid), vec![1], Some(vec![1])),
+				Err(ChildWriteError::InvalidKeyspace(InvalidChildKeyspace)),
+			);
+			assert!(overlay.child_changes(invalid).is_none());
+		}
+		assert_eq!(overlay.children.len(), 1);
+	}
+
+	#[test]
+	fn clone_committed_only_drops_transactions() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let other = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_offchain_storage(b"off", Some(b"chain"));
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![10]));
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_child_storage(&other, vec![1], Some(vec![1]));
+		overlay.set_offchain_storage(b"off", None);
+
+		let committed = overlay.clone_committed_only();
+		assert_eq!(committed.transaction_depth(), 0);
+		assert_eq!(committed.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(committed.storage(&[2]), Some(None));
+		assert_eq!(committed.storage(&[3]), None);
+		assert_extrinsics(&committed.top, vec![1], vec![1]);
+		assert_eq!(committed.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert!(committed.child_changes(other.storage_key()).is_none());
+		assert_eq!(committed.offchain().iter().count(), 1);
+
+		overlay.rollback_transaction().unwrap();
+		let rolled_back = |overlay: &OverlayedChanges| overlay.changes()
+			.map(|(k, v)| (k.clone(), v.value().cloned()))
+			.collect::<StorageCollection>();
+		assert_eq!(rolled_back(&committed), rolled_back(&overlay));
+	}
+
+	#[test]
+	fn clear_child_all_can_be_rolled_back() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let storage_key = child_info.storage_key();
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.clear_child_all(storage_key), 0);
+		assert_eq!(overlay.child_trie_count(), 0);
+
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![3], None);
+
+		overlay.start_transaction();
+		assert_eq!(overlay.clear_child_all(storage_key), 2);
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(None));
+		assert_eq!(overlay.child_storage(&child_info, &[2]), Some(None));
+		overlay.rollback_transaction().unwrap();
+
+		assert_eq!(overlay.child_storage(&child_info, &[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.child_storage(&child_info, &[3]), Some(None));
+	}
+
+	#[test]
+	fn has_prospective_changes_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		assert!(!overlay.has_prospective_changes());
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert!(!overlay.has_prospective_changes());
+
+		overlay.start_transaction();
+		assert!(!overlay.has_prospective_changes());
+		overlay.set_storage(vec![2], Some(vec![2]));
+		assert!(overlay.has_prospective_changes());
+		overlay.commit_transaction().unwrap();
+		assert!(!overlay.has_prospective_changes());
+
+		overlay.start_transaction();
+		overlay.set_child_storage(&child_info, vec![1], None);
+		assert!(overlay.has_prospective_changes());
+		overlay.rollback_transaction().unwrap();
+		assert!(!overlay.has_prospective_changes());
+	}
+
+	#[test]
+	fn child_info_is_returned_per_keyspace() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child2, vec![1], None);
+
+		assert_eq!(overlay.child_info(b"Child1"), Some(&child1));
+		assert_eq!(overlay.child_info(b"Child2"), Some(&child2));
+		assert_eq!(overlay.child_info(b"Child3"), None);
+	}
+
+	#[test]
+	fn child_trie_counts_work() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!((overlay.child_trie_count(), overlay.non_empty_child_trie_count()), (0, 0));
+
+		overlay.set_child_storage(&child1, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child2, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child2, vec![2], Some(vec![2]));
+		assert_eq!((overlay.child_trie_count(), overlay.non_empty_child_trie_count()), (2, 2));
+
+		overlay.clear_child_storage(&child2);
+		assert_eq!((overlay.child_trie_count(), overlay.non_empty_child_trie_count()), (2, 1));
+	}
+
+	#[test]
+	fn deleted_keys_yields_tombstones() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![3], None);
+		overlay.set_child_storage(&child_info, vec![4], None);
+		overlay.set_child_storage(&child_info, vec![5], Some(vec![5]));
+
+		assert_eq!(overlay.deleted_keys(None).collect::<Vec<_>>(), vec![&[2][..], &[3][..]]);
+		assert_eq!(
+			overlay.deleted_keys(Some(child_info.storage_key())).collect::<Vec<_>>(),
+			vec![&[4][..]],
+		);
+		assert_eq!(overlay.deleted_keys(Some(b"Child2")).count(), 0);
+	}
+
 	#[test]
 	fn next_storage_key_change_works() {
 		let mut overlay = OverlayedChanges::default();
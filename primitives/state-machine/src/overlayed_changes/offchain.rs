@@ -45,6 +45,11 @@ impl OffchainOverlayedChanges {
 		self.0.changes().map(|kv| (kv.0, kv.1.value_ref()))
 	}
 
+	/// Create a copy holding only the committed changes.
+	pub fn clone_committed(&self) -> Self {
+		Self(self.0.clone_committed())
+	}
+
 	/// Drain all elements of changeset.
 	pub fn drain(&mut self) -> impl Iterator<Item = OffchainOverlayedChangesItemOwned> {
 		sp_std::mem::take(self).into_iter()
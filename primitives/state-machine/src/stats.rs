@@ -81,10 +81,10 @@ pub struct StateMachineStats {
 impl StateMachineStats {
 	/// Accumulates some registered stats.
 	pub fn add(&self, other: &StateMachineStats) {
-		*self.reads_modified.borrow_mut() += *other.reads_modified.borrow();
-		*self.bytes_read_modified.borrow_mut() += *other.bytes_read_modified.borrow();
-		*self.writes_overlay.borrow_mut() += *other.writes_overlay.borrow();
-		*self.bytes_writes_overlay.borrow_mut() += *other.bytes_writes_overlay.borrow();
+		saturating_add(&self.reads_modified, *other.reads_modified.borrow());
+		saturating_add(&self.bytes_read_modified, *other.bytes_read_modified.borrow());
+		saturating_add(&self.writes_overlay, *other.writes_overlay.borrow());
+		saturating_add(&self.bytes_writes_overlay, *other.bytes_writes_overlay.borrow());
 	}
 }
 
@@ -110,22 +110,59 @@ impl UsageInfo {
 	}
 	/// Add collected state machine to this state.
 	pub fn include_state_machine_states(&mut self, count: &StateMachineStats) {
-		self.modified_reads.ops += *count.reads_modified.borrow();
-		self.modified_reads.bytes += *count.bytes_read_modified.borrow();
-		self.overlay_writes.ops += *count.writes_overlay.borrow();
-		self.overlay_writes.bytes += *count.bytes_writes_overlay.borrow();
+		self.modified_reads.ops = self.modified_reads.ops.saturating_add(*count.reads_modified.borrow());
+		self.modified_reads.bytes = self.modified_reads.bytes
+			.saturating_add(*count.bytes_read_modified.borrow());
+		self.overlay_writes.ops = self.overlay_writes.ops.saturating_add(*count.writes_overlay.borrow());
+		self.overlay_writes.bytes = self.overlay_writes.bytes
+			.saturating_add(*count.bytes_writes_overlay.borrow());
 	}
 }
 
 impl StateMachineStats {
 	/// Tally one read modified operation, of some length.
 	pub fn tally_read_modified(&self, data_bytes: u64) {
-		*self.reads_modified.borrow_mut() += 1;
-		*self.bytes_read_modified.borrow_mut() += data_bytes;
+		saturating_add(&self.reads_modified, 1);
+		saturating_add(&self.bytes_read_modified, data_bytes);
 	}
 	/// Tally one write overlay operation, of some length.
 	pub fn tally_write_overlay(&self, data_bytes: u64) {
-		*self.writes_overlay.borrow_mut() += 1;
-		*self.bytes_writes_overlay.borrow_mut() += data_bytes;
+		saturating_add(&self.writes_overlay, 1);
+		saturating_add(&self.bytes_writes_overlay, data_bytes);
+	}
+}
+
+/// Add `value` to the counter, saturating at the numeric bound instead of overflowing.
+fn saturating_add(counter: &RefCell<u64>, value: u64) {
+	let mut counter = counter.borrow_mut();
+	*counter = counter.saturating_add(value);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tally_saturates_instead_of_overflowing() {
+		let stats = StateMachineStats::default();
+		*stats.writes_overlay.borrow_mut() = u64::MAX - 1;
+		*stats.bytes_writes_overlay.borrow_mut() = u64::MAX - 10;
+
+		stats.tally_write_overlay(5);
+		assert_eq!(*stats.writes_overlay.borrow(), u64::MAX);
+		assert_eq!(*stats.bytes_writes_overlay.borrow(), u64::MAX - 5);
+
+		stats.tally_write_overlay(u64::MAX);
+		assert_eq!(*stats.writes_overlay.borrow(), u64::MAX);
+		assert_eq!(*stats.bytes_writes_overlay.borrow(), u64::MAX);
+
+		stats.tally_read_modified(1);
+		assert_eq!(*stats.reads_modified.borrow(), 1);
+
+		let total = StateMachineStats::default();
+		total.add(&stats);
+		total.add(&stats);
+		assert_eq!(*total.writes_overlay.borrow(), u64::MAX);
+		assert_eq!(*total.reads_modified.borrow(), 2);
 	}
 }